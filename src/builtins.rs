@@ -1,4 +1,4 @@
-use crate::share::TransExt;
+use crate::share::{TransExt, U_KINDS, U_MEM};
 use rustdb::alloc::{LBox, LRc, LString, LVec};
 use rustdb::{
     Block, BuiltinMap, CExp, CExpPtr, CompileFunc, DataKind, EvalEnv, Expr, GenTransaction, Value,
@@ -8,7 +8,7 @@ use rustdb::{
 /// Get BuiltinMap
 pub fn get_bmap() -> BuiltinMap {
     // Construct map of "builtin" functions that can be called in SQL code.
-    // Include extra functions ARGON, EMAILTX and SLEEP as well as the standard functions.
+    // Include extra functions ARGON, EMAILTX, SLEEP, SETCOOKIE etc. as well as the standard functions.
     let mut bmap = BuiltinMap::default();
     standard_builtins(&mut bmap);
     let list = [
@@ -19,6 +19,11 @@ pub fn get_bmap() -> BuiltinMap {
         ("SETDOS", DataKind::Int, CompileFunc::Int(c_setdos)),
         ("TRANSWAIT", DataKind::Int, CompileFunc::Int(c_trans_wait)),
         ("TRANSFLUSH", DataKind::Int, CompileFunc::Int(c_trans_flush)),
+        ("LOGSTREAM", DataKind::Int, CompileFunc::Int(c_log_stream)),
+        ("LOGFORWARD", DataKind::Int, CompileFunc::Int(c_log_forward)),
+        ("REPINFO", DataKind::String, CompileFunc::Value(c_rep_info)),
+        ("PROMOTE", DataKind::Int, CompileFunc::Int(c_promote)),
+        ("UNFENCE", DataKind::Int, CompileFunc::Int(c_unfence)),
         ("TOPDF", DataKind::Int, CompileFunc::Int(c_topdf)),
         ("BINPACK", DataKind::Binary, CompileFunc::Value(c_binpack)),
         (
@@ -35,6 +40,67 @@ pub fn get_bmap() -> BuiltinMap {
         ("NOLOG", DataKind::Int, CompileFunc::Int(c_nolog)),
         ("ADLER", DataKind::Int, CompileFunc::Int(c_adler)),
         ("DOLOG", DataKind::Int, CompileFunc::Int(c_dolog)),
+        ("SETCOOKIE", DataKind::Int, CompileFunc::Int(c_setcookie)),
+        ("COOKIE", DataKind::String, CompileFunc::Value(c_cookie)),
+        ("NEWTOKEN", DataKind::String, CompileFunc::Value(c_newtoken)),
+        ("SHA", DataKind::Binary, CompileFunc::Value(c_sha)),
+        ("DEFER", DataKind::Int, CompileFunc::Int(c_defer)),
+        (
+            "CSRFTOKEN",
+            DataKind::String,
+            CompileFunc::Value(c_csrf_token),
+        ),
+        (
+            "CSRFFIELD",
+            DataKind::String,
+            CompileFunc::Value(c_csrf_field),
+        ),
+        (
+            "TOTPSECRET",
+            DataKind::String,
+            CompileFunc::Value(c_totp_secret),
+        ),
+        ("TOTPURI", DataKind::String, CompileFunc::Value(c_totp_uri)),
+        ("TOTPVERIFY", DataKind::Int, CompileFunc::Int(c_totp_verify)),
+        ("PWHASH", DataKind::String, CompileFunc::Value(c_pw_hash)),
+        ("PWVERIFY", DataKind::Int, CompileFunc::Int(c_pw_verify)),
+        (
+            "PWNEEDSREHASH",
+            DataKind::Int,
+            CompileFunc::Int(c_pw_needs_rehash),
+        ),
+        ("LOGINFAIL", DataKind::Int, CompileFunc::Int(c_login_fail)),
+        ("RATECOST", DataKind::Int, CompileFunc::Int(c_rate_cost)),
+        ("DOSINFO", DataKind::String, CompileFunc::Value(c_dos_info)),
+        ("DOSRESET", DataKind::Int, CompileFunc::Int(c_dos_reset)),
+        (
+            "OIDCAUTHURL",
+            DataKind::String,
+            CompileFunc::Value(c_oidc_auth_url),
+        ),
+        (
+            "OIDCCLAIMS",
+            DataKind::String,
+            CompileFunc::Value(c_oidc_claims),
+        ),
+        ("JSONGET", DataKind::String, CompileFunc::Value(c_json_get)),
+        ("JWTSIGN", DataKind::String, CompileFunc::Value(c_jwt_sign)),
+        (
+            "JWTVERIFY",
+            DataKind::String,
+            CompileFunc::Value(c_jwt_verify),
+        ),
+        ("AUDIT", DataKind::Int, CompileFunc::Int(c_audit)),
+        (
+            "AUDITVERIFY",
+            DataKind::Int,
+            CompileFunc::Int(c_audit_verify),
+        ),
+        (
+            "AUDITLAST",
+            DataKind::Binary,
+            CompileFunc::Value(c_audit_last),
+        ),
     ];
     for (name, typ, cf) in list {
         bmap.insert(Box::from(name), (typ, cf));
@@ -96,7 +162,7 @@ fn c_shutdown(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
     lbox!(Shutdown { code })
 }
 
-/// Compiled call to SLEEP
+/// Compiled call to SHUTDOWN. The server terminates after the transaction completes.
 struct Shutdown {
     code: CExpPtr<i64>,
 }
@@ -105,38 +171,98 @@ impl CExp<i64> for Shutdown {
         let code = self.code.eval(ee, d);
         let mut ext = ee.tr.get_extension();
         if let Some(ext) = ext.downcast_mut::<TransExt>() {
-            if let Some(ss) = &ext.ss
-            {
-                ss.terminate(code);
-            }
+            ext.audit("SHUTDOWN", &format!("code={code}"), "");
+            ext.shutdown = Some(code);
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to RATECOST.
+fn c_rate_cost(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int]);
+    let cost = c_int(b, &mut args[0]);
+    lbox!(RateCost { cost })
+}
+
+/// Compiled call to RATECOST( cost ). Sets the cost of the request ( default 1 ), taken from the requests count limit.
+struct RateCost {
+    cost: CExpPtr<i64>,
+}
+impl CExp<i64> for RateCost {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let cost = self.cost.eval(ee, d).max(1) as u64;
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.cost = cost;
         }
         ee.tr.set_extension(ext);
         0
     }
 }
 
-/// Compile call to SETDOS.
+/// Compile call to DOSINFO.
+fn c_dos_info(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    lbox!(DosInfo {})
+}
+
+/// Compiled call to DOSINFO. Result is a JSON array of current usage information, highest percentage used first.
+/// Each element has uid, used, limit, rate ( arrays for count, read, cpu and write ), pct, fails and retry ( seconds ).
+struct DosInfo {}
+impl CExp<Value> for DosInfo {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let info = match shared_state(ee) {
+            Some(ss) => ss.u_info(),
+            None => "[]".to_string(),
+        };
+        Value::String(LRc::new(LString::from(&*info)))
+    }
+}
+
+/// Compile call to DOSRESET.
+fn c_dos_reset(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    let args = c_strings(b, args, 1);
+    lbox!(DosReset { args })
+}
+
+/// Compiled call to DOSRESET( uid ). Clears usage and failed logins for uid ( all if blank ), keeping limits.
+/// Result is the number of entries reset.
+struct DosReset {
+    args: Vec<CExpPtr<Value>>,
+}
+impl CExp<i64> for DosReset {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let a = eval_strings(&self.args, ee, d);
+        let mut result = 0;
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>()
+            && let Some(ss) = &ext.ss
+        {
+            result = ss.u_reset(&a[0]) as i64;
+            ext.audit("DOSRESET", &format!("uid={}", a[0]), "");
+        }
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
+/// Compile call to SETDOS. The memory limit is optional.
 fn c_setdos(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
-    check_types(
-        b,
-        args,
-        &[
-            DataKind::String,
-            DataKind::Int,
-            DataKind::Int,
-            DataKind::Int,
-            DataKind::Int,
-        ],
-    );
+    let mut kinds = vec![DataKind::String];
+    kinds.extend([DataKind::Int; U_KINDS]);
+    let n = args.len().clamp(U_KINDS, U_KINDS + 1);
+    check_types(b, args, &kinds[..n]);
     let uid = c_value(b, &mut args[0]);
     let mut to = Vec::new();
-    for i in 0..4 {
-        to.push(c_int(b, &mut args[i + 1]));
+    for a in &mut args[1..] {
+        to.push(c_int(b, a));
     }
     lbox!(SetDos { uid, to })
 }
 
-/// Compiled call to SETDOS
+/// Compiled call to SETDOS( uid, count, read, cpu, write [, mem] ). If mem is omitted, the default memory limit is used.
 struct SetDos {
     uid: CExpPtr<Value>,
     to: Vec<CExpPtr<i64>>,
@@ -145,13 +271,27 @@ impl CExp<i64> for SetDos {
     fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
         let mut result = 1;
         let uid = self.uid.eval(ee, d).str().to_string();
-        let mut to = [0; 4];
-        for (i, item) in to.iter_mut().enumerate() {
-            *item = self.to[i].eval(ee, d) as u64;
+        let mut to = [0; U_KINDS];
+        for (i, item) in self.to.iter().enumerate() {
+            to[i] = item.eval(ee, d) as u64;
         }
         let mut ext = ee.tr.get_extension();
         if let Some(ext) = ext.downcast_mut::<TransExt>() {
             ext.uid = uid.clone();
+            if self.to.len() < U_KINDS
+                && let Some(ss) = &ext.ss
+            {
+                to[U_MEM] = ss.dos_limit[U_MEM];
+            }
+            // Record changes to limits that were previously set, not the same limits being set for each request.
+            if let Some(ss) = &ext.ss
+                && let Some(from) = ss.u_limits(&uid)
+                && from != [0; U_KINDS]
+                && from != to
+            {
+                let detail = format!("uid={uid} from={from:?} to={to:?}");
+                ext.audit("SETDOS", &detail, "");
+            }
             if !ext.set_dos(uid, to) {
                 result = 0;
             }
@@ -199,6 +339,117 @@ impl CExp<i64> for TransWait {
     }
 }
 
+/// Compile call to LOGSTREAM.
+fn c_log_stream(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int]);
+    let from = c_int(b, &mut args[0]);
+    lbox!(LogStream { from })
+}
+
+/// Compiled call to LOGSTREAM
+struct LogStream {
+    from: CExpPtr<i64>,
+}
+impl CExp<i64> for LogStream {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let from = self.from.eval(ee, d);
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.log_stream = Some(from.max(0) as u64);
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to LOGFORWARD.
+fn c_log_forward(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    lbox!(LogForward {})
+}
+
+/// Compiled call to LOGFORWARD. The request forwarded by a replication server ( the file part named data )
+/// is run after the transaction, and the response is sent back ( see replicate::run_forwarded ).
+struct LogForward {}
+impl CExp<i64> for LogForward {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.log_forward = true;
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to REPINFO.
+fn c_rep_info(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    lbox!(RepInfo {})
+}
+
+/// Compiled call to REPINFO. Result is a JSON object with the replication status ( see replicate::info ).
+struct RepInfo {}
+impl CExp<Value> for RepInfo {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let info = match shared_state(ee) {
+            Some(ss) => crate::replicate::info(&ss),
+            None => "{}".to_string(),
+        };
+        Value::String(LRc::new(LString::from(&*info)))
+    }
+}
+
+/// Compile call to PROMOTE.
+fn c_promote(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    lbox!(Promote {})
+}
+
+/// Compiled call to PROMOTE. The replication server is promoted to master after the transaction ( see tasks::promote ).
+/// Result is 1 if the server is a replication server that will be promoted, otherwise 0.
+struct Promote {}
+impl CExp<i64> for Promote {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut result = 0;
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>()
+            && let Some(ss) = &ext.ss
+            && ss.backup.lock().unwrap().is_some()
+        {
+            ext.promote = true;
+            result = 1;
+        }
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
+/// Compile call to UNFENCE.
+fn c_unfence(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    lbox!(Unfence {})
+}
+
+/// Compiled call to UNFENCE. The server accepts updates again after the transaction ( see SharedState::unfence ).
+/// Result is 1 if the server has been fenced, otherwise 0.
+struct Unfence {}
+impl CExp<i64> for Unfence {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut result = 0;
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>()
+            && let Some(ss) = &ext.ss
+            && ss.is_fenced()
+        {
+            ext.unfence = true;
+            result = 1;
+        }
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
 /// Compile call to TRANSFLUSH.
 fn c_trans_flush(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
     check_types(b, args, &[]);
@@ -290,6 +541,11 @@ impl CExp<i64> for SetMem {
     fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
         let to = self.to.eval(ee, d) as usize;
         ee.db.set_stash_mem_limit(to * 1024 * 1024);
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.audit("SETMEM", &format!("mb={to}"), "");
+        }
+        ee.tr.set_extension(ext);
         0
     }
 }
@@ -309,12 +565,17 @@ struct Deserialise {
 impl CExp<Value> for Deserialise {
     fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
         let ser = self.ser.eval(ee, d);
-        let qy: rustdb::gentrans::GenQuery = bincode::deserialize(ser.bina()).unwrap();
-        let s = serde_json::to_string(&qy).unwrap();
+        let s = deserialise(ser.bina());
         Value::String(LRc::new(LString::from(&*s)))
     }
 }
 
+/// Convert a serialised transaction query ( see log.Transaction ) to JSON.
+pub fn deserialise(ser: &[u8]) -> String {
+    let qy: rustdb::gentrans::GenQuery = bincode::deserialize(ser).unwrap();
+    serde_json::to_string(&qy).unwrap()
+}
+
 /// Compile call to DOLOG.
 fn c_dolog(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
     check_types(b, args, &[DataKind::Binary]);
@@ -331,6 +592,15 @@ impl CExp<i64> for DoLog {
         let ser = self.ser.eval(ee, d);
         let mut tr = GenTransaction::new();
         tr.qy = bincode::deserialize(ser.bina()).unwrap();
+        // The logged transaction may need the shared state ( e.g. for the server key ).
+        let ext = ee.tr.get_extension();
+        let mut log_ext = TransExt::new();
+        if let Some(e) = ext.downcast_ref::<TransExt>() {
+            log_ext.ss = e.ss.clone();
+            log_ext.key = e.key;
+        }
+        ee.tr.set_extension(ext);
+        tr.ext = log_ext;
         let sql = tr.qy.sql.clone();
         ee.db.run(&sql, &mut tr);
         if ee.db.function_update() { 1 } else { 0 }
@@ -373,3 +643,666 @@ impl CExp<i64> for Adler {
         flate3::adler32(bytes.bina()) as i64
     }
 }
+
+/// Get the server key.
+fn server_key(ee: &mut EvalEnv) -> crate::crypt::Key {
+    let ext = ee.tr.get_extension();
+    let key = match ext.downcast_ref::<TransExt>() {
+        Some(TransExt { ss: Some(ss), .. }) => Some(ss.key),
+        Some(TransExt { key, .. }) => *key,
+        _ => None,
+    };
+    ee.tr.set_extension(ext);
+    key.expect("server key not available")
+}
+
+/// Get the password hashing parameters.
+fn pw_params(ee: &mut EvalEnv) -> argon2::Params {
+    let ext = ee.tr.get_extension();
+    let params = match ext.downcast_ref::<TransExt>() {
+        Some(TransExt { ss: Some(ss), .. }) => ss.pw_params.clone(),
+        _ => argon2::Params::default(),
+    };
+    ee.tr.set_extension(ext);
+    params
+}
+
+/// SETCOOKIE/COOKIE flag : omit HttpOnly ( allow script access ).
+const CF_SCRIPT: i64 = 1;
+/// SETCOOKIE/COOKIE flag : omit Secure ( allow plain http ).
+const CF_INSECURE: i64 = 2;
+/// SETCOOKIE/COOKIE flag : SameSite=Strict ( default is Lax ).
+const CF_STRICT: i64 = 4;
+/// SETCOOKIE/COOKIE flag : SameSite=None.
+const CF_CROSS_SITE: i64 = 8;
+/// SETCOOKIE/COOKIE flag : value is signed.
+const CF_SIGNED: i64 = 16;
+/// SETCOOKIE/COOKIE flag : value is encrypted.
+const CF_ENCRYPTED: i64 = 32;
+
+/// Compile call to SETCOOKIE.
+fn c_setcookie(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(
+        b,
+        args,
+        &[
+            DataKind::String,
+            DataKind::String,
+            DataKind::Int,
+            DataKind::String,
+            DataKind::Int,
+        ],
+    );
+    let name = c_value(b, &mut args[0]);
+    let value = c_value(b, &mut args[1]);
+    let maxage = c_int(b, &mut args[2]);
+    let path = c_value(b, &mut args[3]);
+    let flags = c_int(b, &mut args[4]);
+    lbox!(SetCookie {
+        name,
+        value,
+        maxage,
+        path,
+        flags
+    })
+}
+
+/// Compiled call to SETCOOKIE
+struct SetCookie {
+    name: CExpPtr<Value>,
+    value: CExpPtr<Value>,
+    maxage: CExpPtr<i64>,
+    path: CExpPtr<Value>,
+    flags: CExpPtr<i64>,
+}
+impl CExp<i64> for SetCookie {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let name = self.name.eval(ee, d).str();
+        let value = self.value.eval(ee, d).str();
+        let maxage = self.maxage.eval(ee, d);
+        let path = self.path.eval(ee, d).str();
+        let flags = self.flags.eval(ee, d);
+
+        let is_token = |c: char| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c);
+        if name.is_empty() || !name.chars().all(is_token) {
+            panic!("SETCOOKIE invalid cookie name");
+        }
+        if path.chars().any(|c| c.is_ascii_control() || c == ';') {
+            panic!("SETCOOKIE invalid path");
+        }
+        let value = if flags & CF_ENCRYPTED != 0 {
+            crate::crypt::encrypt(&server_key(ee), &name, &value)
+        } else if flags & CF_SIGNED != 0 {
+            crate::crypt::sign(&server_key(ee), &name, &value)
+        } else {
+            value.to_string()
+        };
+        let mut h = format!("{}={}", name, urlencoding::encode(&value));
+        h.push_str("; Path=");
+        h.push_str(if path.is_empty() { "/" } else { &path });
+        if maxage >= 0 {
+            h.push_str(&format!("; Max-Age={maxage}"));
+        }
+        if flags & CF_SCRIPT == 0 {
+            h.push_str("; HttpOnly");
+        }
+        if flags & CF_INSECURE == 0 {
+            h.push_str("; Secure");
+        }
+        h.push_str(if flags & CF_CROSS_SITE != 0 {
+            "; SameSite=None"
+        } else if flags & CF_STRICT != 0 {
+            "; SameSite=Strict"
+        } else {
+            "; SameSite=Lax"
+        });
+        ee.tr.header("set-cookie", &h);
+        if name.as_str() == crate::crypt::SESSION_COOKIE {
+            let mut ext = ee.tr.get_extension();
+            if let Some(ext) = ext.downcast_mut::<TransExt>() {
+                ext.session = Some(value);
+            }
+            ee.tr.set_extension(ext);
+        }
+        0
+    }
+}
+
+/// Compile call to COOKIE.
+fn c_cookie(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String, DataKind::Int]);
+    let name = c_value(b, &mut args[0]);
+    let flags = c_int(b, &mut args[1]);
+    lbox!(Cookie { name, flags })
+}
+
+/// Compiled call to COOKIE
+struct Cookie {
+    name: CExpPtr<Value>,
+    flags: CExpPtr<i64>,
+}
+impl CExp<Value> for Cookie {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let name = self.name.eval(ee, d).str();
+        let flags = self.flags.eval(ee, d);
+        let value = ee.tr.arg(3, &name);
+        let result = if value.is_empty() {
+            None
+        } else if flags & CF_ENCRYPTED != 0 {
+            crate::crypt::decrypt(&server_key(ee), &name, &value)
+        } else if flags & CF_SIGNED != 0 {
+            crate::crypt::unsign(&server_key(ee), &name, &value).map(|v| v.to_string())
+        } else {
+            Some(value.to_string())
+        };
+        let result = result.unwrap_or_default();
+        Value::String(LRc::new(LString::from(&*result)))
+    }
+}
+
+/// Compile call to NEWTOKEN.
+fn c_newtoken(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    lbox!(NewToken {})
+}
+
+/// Compiled call to NEWTOKEN
+struct NewToken {}
+impl CExp<Value> for NewToken {
+    fn eval(&self, _ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let token = crate::crypt::new_token();
+        Value::String(LRc::new(LString::from(&*token)))
+    }
+}
+
+/// Compile call to SHA ( SHA-256 hash ).
+fn c_sha(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let s = c_value(b, &mut args[0]);
+    lbox!(Sha256 { s })
+}
+
+/// Compiled call to SHA
+struct Sha256 {
+    s: CExpPtr<Value>,
+}
+impl CExp<Value> for Sha256 {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let s = self.s.eval(ee, d).str();
+        let hash = crate::crypt::sha256(s.as_bytes());
+        Value::RcBinary(LRc::new(LVec::from(&hash[..])))
+    }
+}
+
+/// Compile call to DEFER.
+fn c_defer(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::String]);
+    let sql = c_value(b, &mut args[0]);
+    lbox!(Defer { sql })
+}
+
+/// Compiled call to DEFER
+struct Defer {
+    sql: CExpPtr<Value>,
+}
+impl CExp<i64> for Defer {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let sql = self.sql.eval(ee, d).str().to_string();
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.deferred.push(sql);
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Get the CSRF token for the current session.
+fn csrf_token(ee: &mut EvalEnv) -> String {
+    let key = server_key(ee);
+    let ext = ee.tr.get_extension();
+    let session = match ext.downcast_ref::<TransExt>() {
+        Some(TransExt {
+            session: Some(s), ..
+        }) => Some(s.clone()),
+        _ => None,
+    };
+    ee.tr.set_extension(ext);
+    // A session cookie set by this transaction takes precedence over the request cookie.
+    let session = session.unwrap_or_else(|| ee.tr.arg(3, crate::crypt::SESSION_COOKIE).to_string());
+    crate::crypt::csrf_token(&key, &session)
+}
+
+/// Compile call to CSRFTOKEN.
+fn c_csrf_token(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    lbox!(CsrfToken {})
+}
+
+/// Compiled call to CSRFTOKEN
+struct CsrfToken {}
+impl CExp<Value> for CsrfToken {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let token = csrf_token(ee);
+        Value::String(LRc::new(LString::from(&*token)))
+    }
+}
+
+/// Compile call to CSRFFIELD.
+fn c_csrf_field(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    lbox!(CsrfField {})
+}
+
+/// Compiled call to CSRFFIELD
+struct CsrfField {}
+impl CExp<Value> for CsrfField {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let field = format!("<input type=hidden name=csrf value={}>", csrf_token(ee));
+        Value::String(LRc::new(LString::from(&*field)))
+    }
+}
+
+/// Compile call to TOTPSECRET.
+fn c_totp_secret(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    lbox!(TotpSecret {})
+}
+
+/// Compiled call to TOTPSECRET
+struct TotpSecret {}
+impl CExp<Value> for TotpSecret {
+    fn eval(&self, _ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let secret = crate::crypt::totp_secret();
+        Value::String(LRc::new(LString::from(&*secret)))
+    }
+}
+
+/// Compile call to TOTPURI.
+fn c_totp_uri(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(
+        b,
+        args,
+        &[DataKind::String, DataKind::String, DataKind::String],
+    );
+    let issuer = c_value(b, &mut args[0]);
+    let account = c_value(b, &mut args[1]);
+    let secret = c_value(b, &mut args[2]);
+    lbox!(TotpUri {
+        issuer,
+        account,
+        secret
+    })
+}
+
+/// Compiled call to TOTPURI
+struct TotpUri {
+    issuer: CExpPtr<Value>,
+    account: CExpPtr<Value>,
+    secret: CExpPtr<Value>,
+}
+impl CExp<Value> for TotpUri {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let issuer = self.issuer.eval(ee, d).str();
+        let account = self.account.eval(ee, d).str();
+        let secret = self.secret.eval(ee, d).str();
+        let (i, a) = (urlencoding::encode(&issuer), urlencoding::encode(&account));
+        let uri = format!(
+            "otpauth://totp/{i}:{a}?secret={secret}&issuer={i}&algorithm=SHA1&digits=6&period=30"
+        );
+        Value::String(LRc::new(LString::from(&*uri)))
+    }
+}
+
+/// Compile call to TOTPVERIFY.
+fn c_totp_verify(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(
+        b,
+        args,
+        &[DataKind::String, DataKind::String, DataKind::Int],
+    );
+    let secret = c_value(b, &mut args[0]);
+    let code = c_value(b, &mut args[1]);
+    let last = c_int(b, &mut args[2]);
+    lbox!(TotpVerify { secret, code, last })
+}
+
+/// Compiled call to TOTPVERIFY
+struct TotpVerify {
+    secret: CExpPtr<Value>,
+    code: CExpPtr<Value>,
+    last: CExpPtr<i64>,
+}
+impl CExp<i64> for TotpVerify {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let secret = self.secret.eval(ee, d).str();
+        let code = self.code.eval(ee, d).str();
+        let last = self.last.eval(ee, d);
+        // Use the transaction time, so the result is the same if the transaction is replicated.
+        let now = ee.tr.global(0) / 1_000_000;
+        crate::crypt::totp_verify(&secret, &code, now, last)
+    }
+}
+
+/// Compile call to PWHASH.
+fn c_pw_hash(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let password = c_value(b, &mut args[0]);
+    lbox!(PwHash { password })
+}
+
+/// Compiled call to PWHASH
+struct PwHash {
+    password: CExpPtr<Value>,
+}
+impl CExp<Value> for PwHash {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let password = self.password.eval(ee, d).str();
+        let hash = crate::crypt::password_hash(&password, pw_params(ee));
+        Value::String(LRc::new(LString::from(&*hash)))
+    }
+}
+
+/// Compile call to PWVERIFY.
+fn c_pw_verify(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let password = c_value(b, &mut args[0]);
+    let hash = c_value(b, &mut args[1]);
+    lbox!(PwVerify { password, hash })
+}
+
+/// Compiled call to PWVERIFY
+struct PwVerify {
+    password: CExpPtr<Value>,
+    hash: CExpPtr<Value>,
+}
+impl CExp<i64> for PwVerify {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let password = self.password.eval(ee, d).str();
+        let hash = self.hash.eval(ee, d).str();
+        crate::crypt::password_verify(&password, &hash) as i64
+    }
+}
+
+/// Compile call to PWNEEDSREHASH.
+fn c_pw_needs_rehash(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::String]);
+    let hash = c_value(b, &mut args[0]);
+    lbox!(PwNeedsRehash { hash })
+}
+
+/// Compiled call to PWNEEDSREHASH
+struct PwNeedsRehash {
+    hash: CExpPtr<Value>,
+}
+impl CExp<i64> for PwNeedsRehash {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let hash = self.hash.eval(ee, d).str();
+        crate::crypt::password_needs_rehash(&hash, pw_params(ee)) as i64
+    }
+}
+
+/// Compile call to LOGINFAIL.
+fn c_login_fail(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    lbox!(LoginFail {})
+}
+
+/// Compiled call to LOGINFAIL
+struct LoginFail {}
+impl CExp<i64> for LoginFail {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut result = 0;
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            result = ext.login_fail() as i64;
+        }
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
+/// Get the shared state ( None if the transaction is not from a request, for example when replaying the log ).
+fn shared_state(ee: &mut EvalEnv) -> Option<std::sync::Arc<crate::share::SharedState>> {
+    let ext = ee.tr.get_extension();
+    let ss = match ext.downcast_ref::<TransExt>() {
+        Some(TransExt { ss: Some(ss), .. }) => Some(ss.clone()),
+        _ => None,
+    };
+    ee.tr.set_extension(ext);
+    ss
+}
+
+/// Compile list of string arguments.
+fn c_strings(b: &Block, args: &mut [Expr], n: usize) -> Vec<CExpPtr<Value>> {
+    check_types(b, args, &vec![DataKind::String; n]);
+    args.iter_mut().map(|a| c_value(b, a)).collect()
+}
+
+/// Evaluate list of string arguments.
+fn eval_strings(args: &[CExpPtr<Value>], ee: &mut EvalEnv, d: &[u8]) -> Vec<String> {
+    args.iter()
+        .map(|a| a.eval(ee, d).str().to_string())
+        .collect()
+}
+
+/// Compile call to OIDCAUTHURL.
+fn c_oidc_auth_url(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    let args = c_strings(b, args, 7);
+    lbox!(OidcAuthUrl { args })
+}
+
+/// Compiled call to OIDCAUTHURL( issuer, clientid, redirect, scopes, state, nonce, verifier )
+struct OidcAuthUrl {
+    args: Vec<CExpPtr<Value>>,
+}
+impl CExp<Value> for OidcAuthUrl {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let a = eval_strings(&self.args, ee, d);
+        let p = crate::oidc::Provider {
+            issuer: a[0].clone(),
+            client_id: a[1].clone(),
+            secret: String::new(),
+            redirect: a[2].clone(),
+        };
+        let result = match shared_state(ee) {
+            Some(ss) => crate::oidc::auth_url(&ss, &p, &a[3], &a[4], &a[5], &a[6]),
+            None => Err("not available".to_string()),
+        };
+        let url = result.unwrap_or_else(|e| {
+            println!("OIDC issuer={} error={}", p.issuer, e);
+            String::new()
+        });
+        Value::String(LRc::new(LString::from(&*url)))
+    }
+}
+
+/// Compile call to OIDCCLAIMS.
+fn c_oidc_claims(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    let args = c_strings(b, args, 7);
+    lbox!(OidcClaims { args })
+}
+
+/// Compiled call to OIDCCLAIMS( issuer, clientid, secret, redirect, code, verifier, nonce )
+struct OidcClaims {
+    args: Vec<CExpPtr<Value>>,
+}
+impl CExp<Value> for OidcClaims {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let a = eval_strings(&self.args, ee, d);
+        let p = crate::oidc::Provider {
+            issuer: a[0].clone(),
+            client_id: a[1].clone(),
+            secret: a[2].clone(),
+            redirect: a[3].clone(),
+        };
+        let now = ee.tr.global(0) / 1_000_000;
+        let result = match shared_state(ee) {
+            Some(ss) => crate::oidc::claims(&ss, &p, &a[4], &a[5], &a[6], now),
+            None => Err("not available".to_string()),
+        };
+        let claims = match result {
+            Ok(claims) => claims.to_string(),
+            Err(e) => {
+                println!("OIDC issuer={} error={}", p.issuer, e);
+                String::new()
+            }
+        };
+        Value::String(LRc::new(LString::from(&*claims)))
+    }
+}
+
+/// Compile call to JSONGET.
+fn c_json_get(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    let args = c_strings(b, args, 2);
+    lbox!(JsonGet { args })
+}
+
+/// Compiled call to JSONGET( json, name ). Strings are returned without quotes, other values as JSON.
+/// If json is an array, name is the ( zero-based ) index of the element.
+struct JsonGet {
+    args: Vec<CExpPtr<Value>>,
+}
+impl CExp<Value> for JsonGet {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let a = eval_strings(&self.args, ee, d);
+        let json: serde_json::Value = serde_json::from_str(&a[0]).unwrap_or_default();
+        let value = match a[1].parse::<usize>() {
+            Ok(i) if json.is_array() => &json[i],
+            _ => &json[&a[1]],
+        };
+        let result = match value {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(s) => s.clone(),
+            v => v.to_string(),
+        };
+        Value::String(LRc::new(LString::from(&*result)))
+    }
+}
+
+/// Compile call to JWTSIGN.
+fn c_jwt_sign(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    let args = c_strings(b, args, 3);
+    lbox!(JwtSign { args })
+}
+
+/// Compiled call to JWTSIGN( alg, key, claims ). Result is blank if the token cannot be signed.
+struct JwtSign {
+    args: Vec<CExpPtr<Value>>,
+}
+impl CExp<Value> for JwtSign {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let a = eval_strings(&self.args, ee, d);
+        let claims = serde_json::from_str(&a[2]).map_err(|e| e.to_string());
+        let token = match claims.and_then(|c| crate::jwt::sign(&a[0], &a[1], &c)) {
+            Ok(token) => token,
+            Err(e) => {
+                println!("JWTSIGN alg={} error={}", a[0], e);
+                String::new()
+            }
+        };
+        Value::String(LRc::new(LString::from(&*token)))
+    }
+}
+
+/// Compile call to JWTVERIFY.
+fn c_jwt_verify(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    let args = c_strings(b, args, 4);
+    lbox!(JwtVerify { args })
+}
+
+/// Compiled call to JWTVERIFY( token, alg, key, aud ). Result is the claims ( JSON ), or blank if the token
+/// is not valid ( signature, expiry, not before, or audience, which is not checked if aud is blank ).
+struct JwtVerify {
+    args: Vec<CExpPtr<Value>>,
+}
+impl CExp<Value> for JwtVerify {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let a = eval_strings(&self.args, ee, d);
+        let now = ee.tr.global(0) / 1_000_000;
+        let claims = match crate::jwt::Jwt::decode(&a[0]) {
+            Some(jwt)
+                if jwt.verify(&a[1], &a[2])
+                    && jwt.check_time(now)
+                    && (a[3].is_empty() || jwt.check_aud(&a[3])) =>
+            {
+                jwt.claims.to_string()
+            }
+            _ => String::new(),
+        };
+        Value::String(LRc::new(LString::from(&*claims)))
+    }
+}
+
+/// Compile call to AUDIT.
+fn c_audit(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    let args = c_strings(b, args, 3);
+    lbox!(Audit { args })
+}
+
+/// Compiled call to AUDIT( action, detail, outcome ). A blank outcome means the transaction error ( if any ) or ok.
+struct Audit {
+    args: Vec<CExpPtr<Value>>,
+}
+impl CExp<i64> for Audit {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let a = eval_strings(&self.args, ee, d);
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.audit(&a[0], &a[1], &a[2]);
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to AUDITVERIFY.
+fn c_audit_verify(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    let mut kinds = vec![DataKind::Binary, DataKind::Int, DataKind::Int];
+    kinds.extend([DataKind::String; 5]);
+    kinds.push(DataKind::Binary);
+    check_types(b, args, &kinds);
+    let args = args.iter_mut().map(|a| c_value(b, a)).collect();
+    lbox!(AuditVerify { args })
+}
+
+/// Compiled call to AUDITVERIFY( prev, time, user, ip, path, action, detail, outcome, hash ).
+/// Result is 1 if hash is the MAC of the audit log record ( see audit.Log ), otherwise 0.
+struct AuditVerify {
+    args: Vec<CExpPtr<Value>>,
+}
+impl CExp<i64> for AuditVerify {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let prev = self.args[0].eval(ee, d);
+        let hash = self.args[8].eval(ee, d);
+        let mut fields = Vec::new();
+        for a in &self.args[1..3] {
+            fields.push(a.eval(ee, d).int().to_string());
+        }
+        fields.extend(eval_strings(&self.args[3..8], ee, d));
+        let key = server_key(ee);
+        crate::crypt::audit_verify(&key, prev.bina(), &fields, hash.bina()) as i64
+    }
+}
+
+/// Compile call to AUDITLAST.
+fn c_audit_last(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    lbox!(AuditLast {})
+}
+
+/// Compiled call to AUDITLAST. Result is the MAC of the last audit log record written since the server started
+/// ( blank if none, or an audit record is being written ).
+struct AuditLast {}
+impl CExp<Value> for AuditLast {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let mut last = Vec::new();
+        if let Some(ss) = shared_state(ee)
+            && let Ok(x) = ss.audit.try_lock()
+            && let Some(x) = &*x
+        {
+            last = x.clone();
+        }
+        Value::RcBinary(LRc::new(LVec::from(&*last)))
+    }
+}
@@ -0,0 +1,366 @@
+use aws_lc_rs::{aead, constant_time, digest, hmac, rand};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+/// Server secret key, used for signing and encrypting cookies.
+pub type Key = [u8; 32];
+
+/// Name of the session cookie ( see login.NewSession ). CSRF tokens are derived from its value.
+pub const SESSION_COOKIE: &str = "sid";
+
+/// Read the server key from the named file, creating it with a random key if it does not exist.
+pub fn load_key(path: &str) -> Result<Key, String> {
+    let mut key = [0; 32];
+    match std::fs::read(path) {
+        Ok(bytes) if bytes.len() == 32 => key.copy_from_slice(&bytes),
+        Ok(bytes) => {
+            let n = bytes.len();
+            return Err(format!("key file {path} should be 32 bytes, not {n}"));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            fill_random(&mut key);
+            write_private(path, &key)
+                .map_err(|e| format!("writing key file {path} failed: {e}"))?;
+        }
+        Err(e) => return Err(format!("reading key file {path} failed: {e}")),
+    }
+    Ok(key)
+}
+
+/// Write a file readable only by the owner.
+fn write_private(path: &str, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut f = opts.open(path)?;
+    f.write_all(data)
+}
+
+/// Fill buffer with random bytes.
+pub fn fill_random(buf: &mut [u8]) {
+    rand::fill(buf).unwrap();
+}
+
+/// Generate a random token ( 256 bits, base64url encoded ).
+pub fn new_token() -> String {
+    let mut bytes = [0; 32];
+    fill_random(&mut bytes);
+    b64(&bytes)
+}
+
+/// Compute SHA-256 hash.
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, data).as_ref().to_vec()
+}
+
+/// Encode bytes as base64url without padding.
+pub fn b64(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Decode base64url ( padding optional ).
+pub fn unb64(s: &str) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(s.trim_end_matches('=')).ok()
+}
+
+/// Encode bytes as lower case hexadecimal.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Derive a sub-key for a specific purpose from the server key.
+fn derive(key: &Key, purpose: &str) -> [u8; 32] {
+    let k = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let mut result = [0; 32];
+    result.copy_from_slice(hmac::sign(&k, purpose.as_bytes()).as_ref());
+    result
+}
+
+/// Sign a cookie value. The result is value.signature.
+pub fn sign(key: &Key, name: &str, value: &str) -> String {
+    let k = hmac::Key::new(hmac::HMAC_SHA256, &derive(key, "cookie-sign"));
+    let tag = hmac::sign(&k, format!("{name}={value}").as_bytes());
+    format!("{value}.{}", b64(tag.as_ref()))
+}
+
+/// Check a signed cookie value, returning the value if the signature is valid.
+pub fn unsign<'a>(key: &Key, name: &str, signed: &'a str) -> Option<&'a str> {
+    let (value, tag) = signed.rsplit_once('.')?;
+    let tag = unb64(tag)?;
+    let k = hmac::Key::new(hmac::HMAC_SHA256, &derive(key, "cookie-sign"));
+    hmac::verify(&k, format!("{name}={value}").as_bytes(), &tag).ok()?;
+    Some(value)
+}
+
+/// Compute the CSRF token for a session, blank if there is no session.
+pub fn csrf_token(key: &Key, session: &str) -> String {
+    if session.is_empty() {
+        return String::new();
+    }
+    let k = hmac::Key::new(hmac::HMAC_SHA256, &derive(key, "csrf"));
+    b64(hmac::sign(&k, session.as_bytes()).as_ref())
+}
+
+/// Check a CSRF token is valid for the session.
+pub fn csrf_verify(key: &Key, session: &str, token: &str) -> bool {
+    let Some(tag) = unb64(token) else {
+        return false;
+    };
+    let k = hmac::Key::new(hmac::HMAC_SHA256, &derive(key, "csrf"));
+    hmac::verify(&k, session.as_bytes(), &tag).is_ok()
+}
+
+/// Compute the MAC of an audit log record, chained to the MAC of the previous record.
+pub fn audit_mac(key: &Key, prev: &[u8], fields: &[String]) -> Vec<u8> {
+    let k = hmac::Key::new(hmac::HMAC_SHA256, &derive(key, "audit"));
+    let mut ctx = hmac::Context::with_key(&k);
+    ctx.update(&(prev.len() as u64).to_be_bytes());
+    ctx.update(prev);
+    for f in fields {
+        ctx.update(&(f.len() as u64).to_be_bytes());
+        ctx.update(f.as_bytes());
+    }
+    ctx.sign().as_ref().to_vec()
+}
+
+/// Check the MAC of an audit log record.
+pub fn audit_verify(key: &Key, prev: &[u8], fields: &[String], mac: &[u8]) -> bool {
+    constant_time::verify_slices_are_equal(&audit_mac(key, prev, fields), mac).is_ok()
+}
+
+/// Get AEAD key for encrypting cookies.
+fn aead_key(key: &Key) -> aead::LessSafeKey {
+    let k = aead::UnboundKey::new(&aead::AES_256_GCM, &derive(key, "cookie-encrypt")).unwrap();
+    aead::LessSafeKey::new(k)
+}
+
+/// Encrypt a cookie value ( AES-256-GCM, the cookie name is authenticated ).
+pub fn encrypt(key: &Key, name: &str, value: &str) -> String {
+    let mut nonce = [0; aead::NONCE_LEN];
+    fill_random(&mut nonce);
+    let mut data = value.as_bytes().to_vec();
+    aead_key(key)
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(name.as_bytes()),
+            &mut data,
+        )
+        .unwrap();
+    let mut result = nonce.to_vec();
+    result.extend_from_slice(&data);
+    b64(&result)
+}
+
+/// Decrypt a cookie value, None if it has been tampered with.
+pub fn decrypt(key: &Key, name: &str, s: &str) -> Option<String> {
+    let mut data = unb64(s)?;
+    if data.len() < aead::NONCE_LEN {
+        return None;
+    }
+    let nonce = aead::Nonce::try_assume_unique_for_key(&data[..aead::NONCE_LEN]).ok()?;
+    let plain = aead_key(key)
+        .open_in_place(
+            nonce,
+            aead::Aad::from(name.as_bytes()),
+            &mut data[aead::NONCE_LEN..],
+        )
+        .ok()?;
+    String::from_utf8(plain.to_vec()).ok()
+}
+
+/// Base32 alphabet ( RFC 4648 ).
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Encode bytes as base32 without padding.
+fn base32(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in bytes {
+        acc = (acc << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32[(acc >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32[(acc << (5 - bits)) as usize & 31] as char);
+    }
+    result
+}
+
+/// Decode base32, ignoring case, spaces and padding.
+fn unbase32(s: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.bytes().filter(|c| *c != b' ' && *c != b'=') {
+        let v = BASE32.iter().position(|x| *x == c.to_ascii_uppercase())? as u32;
+        acc = (acc << 5) | v;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((acc >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+/// TOTP time step in seconds.
+const TOTP_PERIOD: i64 = 30;
+
+/// Generate a new TOTP secret ( 160 bits, base32 encoded ).
+pub fn totp_secret() -> String {
+    let mut bytes = [0; 20];
+    fill_random(&mut bytes);
+    base32(&bytes)
+}
+
+/// Compute HOTP value ( RFC 4226 ) for counter.
+fn hotp(key: &hmac::Key, counter: u64) -> u32 {
+    let tag = hmac::sign(key, &counter.to_be_bytes());
+    let h = tag.as_ref();
+    let i = (h[h.len() - 1] & 15) as usize;
+    let v = u32::from_be_bytes([h[i], h[i + 1], h[i + 2], h[i + 3]]) & 0x7fff_ffff;
+    v % 1_000_000
+}
+
+/// Verify a TOTP code ( RFC 6238, 6 digits, 30 second steps ), allowing one step of clock drift.
+/// Steps up to and including last are rejected, so a code cannot be used twice.
+/// Returns the matching time step, or 0 if the code is not valid.
+pub fn totp_verify(secret: &str, code: &str, now_secs: i64, last: i64) -> i64 {
+    let code = code.trim();
+    let Some(secret) = unbase32(secret) else {
+        return 0;
+    };
+    if secret.is_empty() || code.len() != 6 {
+        return 0;
+    }
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &secret);
+    let now = now_secs / TOTP_PERIOD;
+    for step in [now - 1, now, now + 1] {
+        let expect = format!("{:06}", hotp(&key, step as u64));
+        if step > last
+            && constant_time::verify_slices_are_equal(expect.as_bytes(), code.as_bytes()).is_ok()
+        {
+            return step;
+        }
+    }
+    0
+}
+
+/// Hash a password ( argon2id with random salt ), result is a PHC string.
+pub fn password_hash(password: &str, params: argon2::Params) -> String {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    let mut salt = [0; 16];
+    fill_random(&mut salt);
+    let salt = SaltString::encode_b64(&salt).unwrap();
+    argon2(params)
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Check a password against a PHC string. The hash comparison is constant-time.
+pub fn password_verify(password: &str, phc: &str) -> bool {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+    match PasswordHash::new(phc) {
+        Ok(hash) => argon2::Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Check whether a PHC string was made with different parameters ( so the password should be hashed again ).
+pub fn password_needs_rehash(phc: &str, params: argon2::Params) -> bool {
+    use argon2::password_hash::PasswordHash;
+    let Ok(hash) = PasswordHash::new(phc) else {
+        return true;
+    };
+    if hash.algorithm != argon2::Algorithm::Argon2id.ident()
+        || hash.version != Some(argon2::Version::V0x13.into())
+    {
+        return true;
+    }
+    match argon2::Params::try_from(&hash) {
+        Ok(p) => {
+            p.m_cost() != params.m_cost()
+                || p.t_cost() != params.t_cost()
+                || p.p_cost() != params.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+/// Get argon2id hasher.
+fn argon2(params: argon2::Params) -> argon2::Argon2<'static> {
+    argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shared secret from RFC 6238 appendix B ( ASCII "12345678901234567890" ), base32 encoded.
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn base32_round_trip() {
+        assert_eq!(base32(b"12345678901234567890"), SECRET);
+        assert_eq!(
+            unbase32(&SECRET.to_lowercase()).unwrap(),
+            b"12345678901234567890"
+        );
+        assert!(unbase32("GEZ1").is_none());
+    }
+
+    #[test]
+    fn totp_rfc6238_vectors() {
+        // SHA1 test vectors, the last 6 of the 8 digits given in the RFC.
+        for (t, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(totp_verify(SECRET, code, t, 0), t / TOTP_PERIOD, "t={t}");
+        }
+    }
+
+    #[test]
+    fn totp_clock_drift() {
+        // Code for step 37037036 ( t=1111111109 ).
+        let step = 1111111109 / TOTP_PERIOD;
+        let at = |step: i64| step * TOTP_PERIOD;
+        assert_eq!(totp_verify(SECRET, "081804", at(step - 1), 0), step);
+        assert_eq!(totp_verify(SECRET, "081804", at(step + 1), 0), step);
+        assert_eq!(totp_verify(SECRET, "081804", at(step - 2), 0), 0);
+        assert_eq!(totp_verify(SECRET, "081804", at(step + 2), 0), 0);
+    }
+
+    #[test]
+    fn totp_replay() {
+        let step = 1111111109 / TOTP_PERIOD;
+        assert_eq!(totp_verify(SECRET, "081804", 1111111109, step - 1), step);
+        assert_eq!(totp_verify(SECRET, "081804", 1111111109, step), 0);
+        assert_eq!(totp_verify(SECRET, "081804", 1111111109, step + 1), 0);
+    }
+
+    #[test]
+    fn totp_invalid() {
+        assert_eq!(totp_verify(SECRET, "81804", 1111111109, 0), 0);
+        assert_eq!(totp_verify(SECRET, "081805", 1111111109, 0), 0);
+        assert_eq!(totp_verify("", "081804", 1111111109, 0), 0);
+        assert_eq!(totp_verify("not base32!", "081804", 1111111109, 0), 0);
+        assert_eq!(
+            totp_verify(SECRET, " 081804 ", 1111111109, 0),
+            1111111109 / TOTP_PERIOD
+        );
+    }
+}
@@ -122,7 +122,7 @@ GO
 CREATE FN [sys].[IncludeSchema]( mode int, s string ) RETURNS bool AS 
 BEGIN
   IF s = 'sys' OR s = 'date' OR s = 'web' OR s = 'log' OR s = 'admin' OR s = 'browse'
-    OR s = 'email' OR s = 'timed' OR s = 'login'
+    OR s = 'email' OR s = 'timed' OR s = 'login' OR s = 'audit'
   RETURN mode = 2
 
   ELSE
@@ -226,7 +226,7 @@ BEGIN
       SET schema = Schema FROM sys.Table WHERE Id = t
       SET sname = sys.SchemaName(schema)
       SET filter = CASE
-        WHEN sname = 'log' OR sname = 'email' OR sname = 'login' OR sname = 'timed'
+        WHEN sname = 'log' OR sname = 'email' OR sname = 'login' OR sname = 'timed' OR sname = 'audit'
           OR tname = '[browse].[Column]' OR tname = '[browse].[Table]' THEN ' WHERE false'
         ELSE '' END
     END    
@@ -789,6 +789,13 @@ BEGIN
 END
 GO
 
+CREATE FN [web].[Bearer]() RETURNS string AS
+BEGIN
+  /* API token from the Authorization header ( passed as a cookie by the Rust program ). */
+  RETURN ARG( 3, '$bearer' )
+END
+GO
+
 CREATE FN [web].[Cookie]( name string ) RETURNS string AS
 BEGIN
   RETURN ARG( 3, name )
@@ -915,9 +922,25 @@ BEGIN
      To delete a cookie use e.g.
 
      EXEC web.SetCookie('username','','Max-Age=0')
+
+     The cookie is HttpOnly, Secure, SameSite=Lax with Path=/.
+     For other attributes use the builtin function SETCOOKIE( name, value, maxage, path, flags ) directly.
+     maxage is in seconds, -1 means temporary cookie. path blank means /. flags are the sum of:
+       1 = allow script access ( no HttpOnly )
+       2 = allow plain http ( no Secure )
+       4 = SameSite=Strict
+       8 = SameSite=None
+      16 = value is signed using the server key
+      32 = value is encrypted using the server key
+     Signed or encrypted cookies are read using COOKIE( name, flags ), which returns blank if the cookie has been tampered with.
   */
+  DECLARE maxage int SET maxage = -1
+  IF SUBSTRING( expires, 1, 8 ) = 'Max-Age=' SET maxage = PARSEINT( SUBSTRING( expires, 9, 20 ) )
   DECLARE x int
-  SET x = HEADER( 'set-cookie', name | '=' | value | '; ' | expires )
+  IF SUBSTRING( expires, 1, 8 ) = 'Expires='
+    SET x = HEADER( 'set-cookie', name | '=' | value | '; ' | expires | '; Path=/; HttpOnly; Secure; SameSite=Lax' )
+  ELSE
+    SET x = SETCOOKIE( name, value, maxage, '', 0 )
 END
 GO
 
@@ -929,7 +952,8 @@ BEGIN
      1000, 
      1000000000000, 
      1000000000,
-     1000000000000 
+     1000000000000,
+     1000000
   )
   IF ok = 0
   BEGIN
@@ -943,7 +967,11 @@ GO
 CREATE FN [web].[SetUser]() AS 
 BEGIN 
   DECLARE dummy int
-  SET dummy = login.user()
+  SET dummy = login.session()
+
+  -- Set the cost of expensive pages ( see login.PathCost ).
+  DECLARE cost int SET cost = Cost FROM login.PathCost WHERE Path = web.Path()
+  IF cost > 0 SET dummy = RATECOST( cost )
 END
 GO
 
@@ -979,17 +1007,19 @@ GO
 
 CREATE FN [browse].[/browse-AddChild]() AS
 BEGIN
-  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
-
   DECLARE c int SET c = browse.fieldid()
 
   DECLARE p int SET p = PARSEINT( web.Query('p') )
   DECLARE t int SET t = Table FROM sys.Column WHERE Id = c
+
+  DECLARE cu int SET cu = login.get( browse.TableRole(t) ) IF cu = 0 RETURN
   DECLARE ex string
   IF web.Form( '$submit' ) != '' 
   BEGIN
-    EXECUTE( browse.InsertSql( t, c, p ) ) 
+    DECLARE sql string SET sql = browse.InsertSql( t, c, p )
+    EXECUTE( sql ) 
     SET ex = EXCEPTION()
+    DECLARE x int SET x = AUDIT( 'Insert', sql, ex )
     IF ex = '' 
     BEGIN
       EXEC web.Redirect( browse.backurl() )       
@@ -1001,7 +1031,7 @@ BEGIN
   EXEC admin.Head( title )
   SELECT '<b>' | title | '</b><br>'
   IF ex != '' SELECT '<p>Error: ' | ex
-  SELECT '<form method=post>' 
+  SELECT '<form method=post>' | CSRFFIELD() 
   EXECUTE( browse.FormInsertSql( t, c ) )
   SELECT '<p><input name="$submit" type=submit value=Save></form>'
   EXEC admin.Trailer()
@@ -1010,17 +1040,19 @@ GO
 
 CREATE FN [browse].[/browse-AddRow]() AS 
 BEGIN 
-  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
-
   DECLARE t int SET t = browse.tableid()
 
+  DECLARE cu int SET cu = login.get( browse.TableRole(t) ) IF cu = 0 RETURN
+
   DECLARE ex string
   IF web.Form( '$submit' ) != '' 
   BEGIN
     DECLARE lastid int
     SET lastid = LASTID()
-    EXECUTE( browse.InsertSql( t, 0, 0 ) ) 
+    DECLARE sql string SET sql = browse.InsertSql( t, 0, 0 )
+    EXECUTE( sql ) 
     SET ex = EXCEPTION()
+    DECLARE x int SET x = AUDIT( 'Insert', sql, ex )
     IF ex = '' 
     BEGIN
       DECLARE ba string SET ba = browse.backargs()
@@ -1031,7 +1063,7 @@ BEGIN
   
   EXEC admin.Head( 'Add ' | browse.TableTitle( t ) )
   IF ex != '' SELECT '<p>Error: ' | web.Encode( ex )
-  SELECT '<form method=post enctype="multipart/form-data">' 
+  SELECT '<form method=post enctype="multipart/form-data">' | CSRFFIELD() 
   EXECUTE( browse.FormInsertSql( t, 0 ) )
 
   SELECT '<p><input name="$submit" type=submit value=Save></form>'
@@ -1054,13 +1086,15 @@ BEGIN
 
   IF web.Form( '$submit' ) != '' 
   BEGIN
-    EXECUTE( browse.UpdateSql( tid, c ) ) 
+    DECLARE sql string SET sql = browse.UpdateSql( tid, c )
+    DECLARE x int SET x = AUDIT( 'Update', sql, '' )
+    EXECUTE( sql ) 
     EXEC web.Redirect( browse.backurl() )  
   END
   ELSE
   BEGIN
     EXEC admin.Head( 'Column ' | colName )
-    SELECT '<h1>Column ' | colName | '</h1><form method=post>' 
+    SELECT '<h1>Column ' | colName | '</h1><form method=post>' | CSRFFIELD() 
     EXECUTE( browse.FormUpdateSql( tid, c ) )
     SELECT '<p><input name="$submit" type=submit value=Save></form>'
     EXEC admin.Trailer()
@@ -1070,17 +1104,20 @@ GO
 
 CREATE FN [browse].[/browse-EditRow]() AS 
 BEGIN 
-  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
   DECLARE t int SET t = browse.tableid()
+  DECLARE cu int SET cu = login.get( browse.TableRole(t) ) IF cu = 0 RETURN
   DECLARE k int SET k = PARSEINT( web.Query('k') )
   DECLARE ex string
   DECLARE submit string SET submit = web.Form( '$submit' )
   IF submit != '' 
   BEGIN
+    DECLARE sql string, x int
     IF submit = 'Save'
     BEGIN
-      EXECUTE( browse.UpdateSql( t, k ) ) 
+      SET sql = browse.UpdateSql( t, k )
+      EXECUTE( sql ) 
       SET ex = EXCEPTION()
+      SET x = AUDIT( 'Update', sql, ex )
       IF ex = '' 
       BEGIN
         EXEC web.Redirect( browse.backurl() )
@@ -1089,7 +1126,9 @@ BEGIN
     END
     ELSE IF submit = 'Delete'
     BEGIN
-      EXECUTE( 'DELETE FROM ' | sys.TableName( t ) | ' WHERE Id =' | k )
+      SET sql = 'DELETE FROM ' | sys.TableName( t ) | ' WHERE Id =' | k
+      SET x = AUDIT( 'Delete', sql, '' )
+      EXECUTE( sql )
       EXEC web.Redirect( browse.backurl() )
       RETURN
     END      
@@ -1098,22 +1137,20 @@ BEGIN
   EXEC admin.Head( 'Edit ' | browse.TableTitle( t ) )
   IF ex != '' SELECT '<p>Error: ' | web.Encode(ex)
 
-  SELECT '<form method=post enctype="multipart/form-data">'  
+  SELECT '<form method=post enctype="multipart/form-data">' | CSRFFIELD()  
   EXECUTE( browse.FormUpdateSql( t, k ) )
   -- SELECT browse.FormUpdateSql( t, k )
   
 
   SELECT '<p><input name="$submit" type=submit value=Save></form>'
 
-  SELECT '<form method=post><input name="$submit" type=submit value=Delete></form>'
+  SELECT '<form method=post>' | CSRFFIELD() | '<input name="$submit" type=submit value=Delete></form>'
   EXEC admin.Trailer()
 END
 GO
 
 CREATE FN [browse].[/browse-File]() AS 
 BEGIN
-   DECLARE uid int SET uid = login.user()
-
    DECLARE k int SET k = PARSEINT( web.Query('k'))
    DECLARE c int SET c = PARSEINT( web.Query('c'))
    DECLARE t int
@@ -1121,6 +1158,8 @@ BEGIN
    DECLARE id int
    SET t = Table, cname = Name FROM sys.Column WHERE Id = c
 
+   DECLARE cu int SET cu = login.get( browse.TableRole(t) ) IF cu = 0 RETURN
+
    FOR id = Id FROM sys.Column WHERE Table = t
    BEGIN
      DECLARE def string SET def = ''
@@ -1197,13 +1236,15 @@ BEGIN
   IF ok != k INSERT INTO browse.Table( Id ) VALUES ( k )
   IF web.Form( '$submit' ) != '' 
   BEGIN
-    EXECUTE( browse.UpdateSql( tid, k ) ) 
+    DECLARE sql string SET sql = browse.UpdateSql( tid, k )
+    DECLARE x int SET x = AUDIT( 'Update', sql, '' )
+    EXECUTE( sql ) 
     EXEC web.Redirect( '/browse-Table?' | browse.tablearg(k) )
   END
   ELSE
   BEGIN
     EXEC admin.Head( 'Browse Info for ' | sys.TableName(k) )
-    SELECT '<form method=post>' 
+    SELECT '<form method=post>' | CSRFFIELD() 
     EXECUTE( browse.FormUpdateSql( tid, k ) )
     SELECT '<p><input name="$submit" type=submit value=Save></form>'
     EXEC admin.Trailer()
@@ -1234,7 +1275,9 @@ BEGIN
     ELSE '??'
     END
 
-    EXECUTE( 'ALTER TABLE' | sys.Dot(s,n) | ' ADD ' | cn | ' ' | t )
+    DECLARE sql string SET sql = 'ALTER TABLE' | sys.Dot(s,n) | ' ADD ' | cn | ' ' | t
+    DECLARE x int SET x = AUDIT( 'AlterTable', sql, '' )
+    EXECUTE( sql )
     DECLARE sid int, tid int, colid int
     SET sid = Id FROM sys.Schema WHERE Name = s
     SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = n
@@ -1252,7 +1295,7 @@ BEGIN
   ORDER BY browse.DatatypeName(Id)
   SET options |= opt
 
-  SELECT '<form method=post><p>New Column Name: <input name=cn>
+  SELECT '<form method=post>' | CSRFFIELD() | '<p>New Column Name: <input name=cn>
 <p>Datatype: <select name=dt>' | options | '<select> 
 <p><input type=submit value="Ok">
 </form>' 
@@ -1278,7 +1321,7 @@ BEGIN
 
   EXEC admin.Head( 'New Table' )
 
-  SELECT '<form method=post><p>New Table Name: <input name=n> <input type=submit value="Ok"></form>' 
+  SELECT '<form method=post>' | CSRFFIELD() | '<p>New Table Name: <input name=n> <input type=submit value="Ok"></form>' 
 
   EXEC admin.Trailer()
 
@@ -1287,10 +1330,10 @@ GO
 
 CREATE FN [browse].[/browse-Row]() AS 
 BEGIN
-  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
-
   DECLARE t int SET t = browse.tableid()
 
+  DECLARE cu int SET cu = login.get( browse.TableRole(t) ) IF cu = 0 RETURN
+
   DECLARE k int SET k = PARSEINT( web.Query('k') )  
 
   EXECUTE( browse.ShowSql(t,k) )
@@ -1299,12 +1342,12 @@ GO
 
 CREATE FN [browse].[/browse-Table]() AS 
 BEGIN 
-  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
-
   DECLARE ba string SET ba = browse.backargs()
 
   DECLARE t int SET t = browse.tableid()
 
+  DECLARE cu int SET cu = login.get( browse.TableRole(t) ) IF cu = 0 RETURN
+
   DECLARE title string SET title = browse.TableTitle( t )
   SET title = title | ' Table'
   EXEC admin.Head( title )
@@ -2172,12 +2215,12 @@ BEGIN
    SET result = CASE
      WHEN kind = 1 OR kind = 2 THEN sys.QuoteName(Name)
 
-     WHEN kind = 3 OR kind = 5 THEN  '' /* Password has to be set after creating user as Id is included as salt */ 
+     WHEN kind = 3 OR kind = 4 THEN  'browse.InputString(' | colid | ',' | '''''' | ')' 
 
-     WHEN kind = 4 THEN  'browse.InputString(' | colid | ',' | '''''' | ')' 
+     WHEN kind = 5 THEN  'login.Update( '''', web.Form(' | sys.SingleQuote(Name) | '))' 
 
      /* If no new password is entered, leave password unchanged */
-     WHEN kind = 6 THEN  'login.Update( ' | Name | ', web.Form(' | sys.SingleQuote(Name) | '),Id)' 
+     WHEN kind = 6 THEN  'login.Update( ' | Name | ', web.Form(' | sys.SingleQuote(Name) | '))' 
      ELSE 'SqlPasswordBADKIND'
    END
 
@@ -2252,6 +2295,15 @@ BEGIN
 END
 GO
 
+CREATE FN [browse].[TableRole]( t int ) RETURNS int AS
+BEGIN
+  /* Role required to browse table t, set by browse.Table.Role. 0 ( not set ) means Admin. Audit tables always require Admin. */
+  SET result = Role FROM browse.Table WHERE Id = t
+  DECLARE s int SET s = Schema FROM sys.Table WHERE Id = t
+  IF result = 0 OR sys.SchemaName( s ) = 'audit' SET result = 1
+END
+GO
+
 CREATE FN [browse].[TableSelect]( colId int, sel int ) RETURNS string AS
 BEGIN
   DECLARE col string SET col = Name FROM sys.Column WHERE Id = colId
@@ -2600,185 +2652,986 @@ GO
 CREATE SCHEMA [login]
 GO
 
-CREATE TABLE [login].[user]([Name] string,[HashedPassword] binary) 
+CREATE TABLE [login].[IpRule]([Network] string,[Path] string,[Allow] int) 
+GO
+
+CREATE TABLE [login].[JwtIssuer]([Name] string,[Issuer] string,[Alg] string,[Key] string,[Audience] string,[UserClaim] string) 
+GO
+
+CREATE TABLE [login].[OidcLink]([Provider] int,[Subject] string,[User] int) 
+GO
+
+CREATE TABLE [login].[OidcProvider]([Name] string,[Issuer] string,[ClientId] string,[ClientSecret] string,[RedirectUri] string,[Scopes] string,[UserClaim] string,[CreateUsers] int,[LinkUsers] int) 
+GO
+
+CREATE TABLE [login].[PathCost]([Path] string,[Cost] int) 
+GO
+
+CREATE TABLE [login].[Permission]([Role] int,[Path] string) 
+GO
+
+CREATE TABLE [login].[Role]([Name] string,[Description] string) 
+GO
+
+CREATE TABLE [login].[Session]([User] int,[TokenHash] binary,[Created] int,[Expires] int) 
+GO
+
+CREATE TABLE [login].[Token]([User] int,[Name] string,[TokenHash] binary,[Scopes] string,[Created] int,[Expires] int,[LastUsed] int,[Revoked] int) 
+GO
+
+CREATE TABLE [login].[Totp]([User] int,[Secret] string,[LastStep] int) 
+GO
+
+CREATE TABLE [login].[UserRole]([User] int,[Role] int) 
+GO
+
+CREATE TABLE [login].[user]([Name] string,[HashedPassword] binary,[Password] string,[FailCount] int,[LockedUntil] int) 
+GO
+
+CREATE INDEX [BySubject] ON [login].[OidcLink]([Subject])
+GO
+
+CREATE INDEX [ByPath] ON [login].[PathCost]([Path])
+GO
+
+CREATE INDEX [ByPath] ON [login].[Permission]([Path])
+GO
+
+CREATE INDEX [ByTokenHash] ON [login].[Session]([TokenHash])
+GO
+
+CREATE INDEX [ByUser] ON [login].[Session]([User])
+GO
+
+CREATE INDEX [ByTokenHash] ON [login].[Token]([TokenHash])
+GO
+
+CREATE INDEX [ByUser] ON [login].[Token]([User])
+GO
+
+CREATE INDEX [ByUser] ON [login].[Totp]([User])
+GO
+
+CREATE INDEX [ByUser] ON [login].[UserRole]([User])
 GO
 
 CREATE FN [login].[/login-logout]() AS 
 BEGIN 
-    EXEC web.SetCookie( 'uid', '', '' )
-    EXEC web.SetCookie( 'hpw', '', '' )
+    DECLARE sid string SET sid = web.Cookie('sid')
+    IF sid != '' 
+    BEGIN
+      DECLARE x int SET x = DEFER( 'DELETE FROM login.Session WHERE TokenHash = ' | SHA(sid) )
+    END
+    EXEC web.SetCookie( 'sid', '', 'Max-Age=0' )
     EXEC admin.Head( 'Logout' )
     SELECT '<p>Logged out.'
     EXEC admin.Trailer()
 END
 GO
 
-CREATE FN [login].[Update]( old binary, new string, id int ) RETURNS binary AS
-BEGIN
-   RETURN
-   CASE 
-   WHEN new = '' THEN old
-   ELSE login.hash(new | id)
-   END
+CREATE FN [login].[/login-oidc]() AS 
+BEGIN 
+  /* Start OpenID Connect login ( authorization code flow with PKCE ) with provider p. 
+     The state, nonce and PKCE code verifier are kept in an encrypted cookie until the callback. */
+  DECLARE p int SET p = PARSEINT( web.Query('p') )
+  DECLARE issuer string, clientid string, redirect string, scopes string
+  SET issuer = Issuer, clientid = ClientId, redirect = RedirectUri, scopes = Scopes FROM login.OidcProvider WHERE Id = p
+
+  DECLARE state string SET state = NEWTOKEN()
+  DECLARE nonce string SET nonce = NEWTOKEN()
+  DECLARE verifier string SET verifier = NEWTOKEN()
+  DECLARE url string
+  IF issuer != '' SET url = OIDCAUTHURL( issuer, clientid, redirect, scopes, state, nonce, verifier )
+  IF url = ''
+  BEGIN
+    EXEC admin.Head( 'Login' )
+    SELECT '<p>The identity provider is not available.'
+    EXEC admin.Trailer()
+    RETURN
+  END
+  DECLARE x int SET x = SETCOOKIE( 'oidc', state | ' ' | nonce | ' ' | verifier | ' ' | p, 600, '', 32 )
+  EXEC web.Redirect( url )
 END
 GO
 
-CREATE FN [login].[get]( role int ) RETURNS int AS
-BEGIN
-  /* Get the current logged in user, if none, output login form. Note: role is not yet checked */
+CREATE FN [login].[/login-oidc-callback]() AS 
+BEGIN 
+  /* The identity provider redirects here after login, with the authorization code. 
+     The request should be read-only ( GET ), as the code is exchanged for an ID token by OIDCCLAIMS. */
+  DECLARE c string SET c = COOKIE( 'oidc', 32 )
+  DECLARE x int SET x = SETCOOKIE( 'oidc', '', 0, '', 0 )
+  DECLARE msg string
+  IF c = '' OR web.Query('state') != SUBSTRING( c, 1, 43 ) 
+    SET msg = 'Login request not found or expired.'
+  ELSE IF web.Query('error') != '' 
+    SET msg = 'The identity provider returned error ' | web.Query('error') | ' ' | web.Query('error_description')
+  ELSE
+  BEGIN
+    DECLARE p int SET p = PARSEINT( SUBSTRING( c, 133, 20 ) )
+    DECLARE issuer string, clientid string, secret string, redirect string
+    SET issuer = Issuer, clientid = ClientId, secret = ClientSecret, redirect = RedirectUri FROM login.OidcProvider WHERE Id = p
+    DECLARE claims string 
+    IF issuer != '' SET claims = OIDCCLAIMS( issuer, clientid, secret, redirect, web.Query('code'), SUBSTRING( c, 89, 43 ), SUBSTRING( c, 45, 43 ) )
+    IF claims = '' 
+      SET msg = 'Login failed.'
+    ELSE IF login.OidcLogin( p, claims ) = 0 
+      SET msg = 'There is no login for ' | JSONGET( claims, 'sub' ) | ' ' | JSONGET( claims, 'email' )
+  END
+  IF msg = ''
+  BEGIN
+    EXEC web.Redirect( '/admin' )
+    RETURN
+  END
+  EXEC admin.Head( 'Login' )
+  SELECT '<p>' | web.Encode( msg )
+  EXEC admin.Trailer()
+END
+GO
 
-  /*
-     Login is initially disabled. Remove or comment out the line below enable Login after Login password has been setup for some user.
-     In addition, the salt string in login.Hash should be changed.
-  */
-  RETURN 1 -- Login disabled.
+CREATE FN [login].[/login-sessions]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(0) IF cu = 0 RETURN
 
-  DECLARE uid int
-  SET uid = login.user()
-  IF uid = 0
+  DECLARE current binary SET current = SHA( web.Cookie('sid') )
+  DECLARE revoke int SET revoke = PARSEINT( web.Form('revoke') )
+  IF revoke > 0
+    DELETE FROM login.Session WHERE Id = revoke AND User = cu
+  IF web.Form('all') != ''
   BEGIN
-    EXEC admin.Head( 'Login' )
-    SELECT '<form method=post>User Name <input name=username><br>Password <input type=password name=password><br><input type=submit value=Login></form>'
+    DELETE FROM login.Session WHERE User = cu
+    EXEC web.SetCookie( 'sid', '', 'Max-Age=0' )
+    EXEC admin.Head( 'Sessions' )
+    SELECT '<p>Logged out everywhere.'
     EXEC admin.Trailer()
+    RETURN
   END
-  RETURN uid
+
+  EXEC admin.Head( 'Sessions' )
+  SELECT '<table><tr><th>Created<th>Expires<th></tr>'
+  SELECT '<tr><td>' | date.MicroSecToString( Created ) 
+     | '<td>' | date.MicroSecToString( Expires )
+     | '<td>' | CASE WHEN TokenHash = current THEN 'Current session' 
+       ELSE '<form method=post>' | CSRFFIELD() | '<input type=hidden name=revoke value=' | Id | '><input type=submit value=Revoke></form>' END
+     | '</tr>'
+  FROM login.Session WHERE User = cu ORDER BY Created
+  SELECT '</table><form method=post>' | CSRFFIELD() | '<input type=hidden name=all value=1><input type=submit value="Log out everywhere"></form>'
+  EXEC admin.Trailer()
 END
 GO
 
-CREATE FN [login].[hash](s string) RETURNS binary AS
-BEGIN
-  SET result = ARGON(s,'Sep 14 2022 saltiness')
+CREATE FN [login].[/login-tokens]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(0) IF cu = 0 RETURN
+
+  DECLARE token string
+  DECLARE revoke int SET revoke = PARSEINT( web.Form('revoke') )
+  IF revoke > 0
+    UPDATE login.Token SET Revoked = date.Ticks() WHERE Id = revoke AND User = cu AND Revoked = 0
+  DECLARE name string SET name = web.Form('name')
+  IF name != ''
+  BEGIN
+    /* The token is only shown once, and only a hash is stored. The token itself is not logged ( it is generated by NEWTOKEN, not part of the request ).
+       The row is inserted by deferred SQL, which is logged with the hash, so replication servers store the same hash
+       ( replaying this request would generate a different token ). */
+    DECLARE days int SET days = PARSEINT( web.Form('days') )
+    DECLARE expires int SET expires = CASE WHEN days > 0 THEN date.Ticks() + days * 86400000000 ELSE 0 END
+    SET token = NEWTOKEN()
+    DECLARE x int SET x = DEFER( 'INSERT INTO login.Token( User, Name, TokenHash, Scopes, Created, Expires, LastUsed, Revoked ) VALUES (' 
+      | cu | ',' | sys.SingleQuote( name ) | ',' | SHA(token) | ',' | sys.SingleQuote( web.Form('scopes') ) | ',' | date.Ticks() | ',' | expires | ',0,0)' )
+  END
+
+  EXEC admin.Head( 'API Tokens' )
+  IF token != '' 
+    SELECT '<p>New token ( copy it now, it will not be shown again ):<p><code>' | token | '</code>'
+  SELECT '<table><tr><th>Name<th>Scopes<th>Created<th>Expires<th>Last used<th></tr>'
+  SELECT '<tr><td>' | web.Encode( Name ) 
+     | '<td>' | CASE WHEN Scopes = '' THEN 'All' ELSE web.Encode( Scopes ) END
+     | '<td>' | date.MicroSecToString( Created ) 
+     | '<td>' | CASE WHEN Expires = 0 THEN 'Never' ELSE date.MicroSecToString( Expires ) END
+     | '<td>' | CASE WHEN LastUsed = 0 THEN '' ELSE date.MicroSecToString( LastUsed ) END
+     | '<td>' | CASE WHEN Revoked > 0 THEN 'Revoked ' | date.MicroSecToString( Revoked ) 
+       ELSE '<form method=post>' | CSRFFIELD() | '<input type=hidden name=revoke value=' | Id | '><input type=submit value=Revoke></form>' END
+     | '</tr>'
+  FROM login.Token WHERE User = cu ORDER BY Created
+  SELECT '</table><h3>New token</h3><form method=post>' | CSRFFIELD() 
+    | 'Name <input name=name required><br>Scopes <input name=scopes> ( role names separated by spaces, blank for all roles )'
+    | '<br>Expires after <input name=days type=number value=90> days ( 0 for never )<br><input type=submit value=Create></form>'
+  EXEC admin.Trailer()
 END
 GO
 
-CREATE FN [login].[user]() RETURNS int AS
-BEGIN
-  DECLARE username string SET username = web.Form('username')
-  DECLARE uid int
+CREATE FN [login].[/login-totp]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(0) IF cu = 0 RETURN
 
-  IF username != ''
+  DECLARE id int SET id = Id FROM login.Totp WHERE User = cu
+  DECLARE code string SET code = web.Form('code')
+  DECLARE secret string SET secret = web.Form('secret')
+  DECLARE msg string
+  IF id > 0 AND code != ''
   BEGIN
-    DECLARE password string SET password = web.Form('password')
-    SET result = Id FROM login.user WHERE Name = username
-    DECLARE hpw binary SET hpw = login.hash( password|result )
-    SET uid = Id FROM login.user WHERE Id = result AND HashedPassword = hpw
-    IF uid > 0
+    DECLARE current string, last int
+    SET current = Secret, last = LastStep FROM login.Totp WHERE Id = id
+    IF TOTPVERIFY( current, code, last ) > 0
     BEGIN
-      EXEC web.SetCookie( 'uid', '' | uid, '' )
-      EXEC web.SetCookie( 'hpw', '' | hpw, '' )
-      IF web.SetDos(uid) = 0 RETURN 0
-      RETURN uid
+      DELETE FROM login.Totp WHERE Id = id
+      SET id = 0
+      SET msg = 'Two-factor authentication has been disabled.'
     END
+    ELSE SET msg = 'Invalid code.'
   END
-
-  DECLARE uids string SET uids = web.Cookie('uid')
-  DECLARE hpwf string SET hpwf = web.Cookie('hpw')
-
-  IF uids != ''
+  ELSE IF id = 0 AND secret != ''
   BEGIN
-    SET uid = PARSEINT(uids)
-    DECLARE hpwt binary SET hpwt = HashedPassword FROM login.user WHERE Id = uid
-    IF hpwf = '' | hpwt 
+    -- The secret is generated when the page is shown, and only saved once a code has been checked.
+    DECLARE step int SET step = TOTPVERIFY( secret, code, 0 )
+    IF step > 0
     BEGIN
-      IF web.SetDos(uid) = 0 RETURN 0
-      RETURN uid
+      INSERT INTO login.Totp( User, Secret, LastStep ) VALUES ( cu, secret, step )
+      SET id = LASTID()
+      SET msg = 'Two-factor authentication has been enabled.'
     END
+    ELSE SET msg = 'Invalid code.'
   END
-  RETURN 0
+
+  EXEC admin.Head( 'Two-factor authentication' )
+  IF msg != '' SELECT '<p>' | msg
+  IF id > 0
+  BEGIN
+    SELECT '<p>Two-factor authentication is enabled. To disable it, enter a code from your authenticator app.'
+      | '<form method=post>' | CSRFFIELD() | 'Code <input name=code autocomplete=one-time-code inputmode=numeric> <input type=submit value=Disable></form>'
+  END
+  ELSE
+  BEGIN
+    IF secret = '' SET secret = TOTPSECRET()
+    DECLARE uri string SET uri = TOTPURI( 'RustWeb', login.UserName(cu), secret )
+    SELECT '<p>To enable two-factor authentication, add this key to your authenticator app ( open the link, or enter the key ), then enter the code shown by the app.'
+      | '<p><a href="' | web.Encode(uri) | '">' | web.Encode(uri) | '</a>'
+      | '<p>Key: ' | secret
+      | '<form method=post>' | CSRFFIELD() | '<input type=hidden name=secret value=' | secret | '>'
+      | 'Code <input name=code autocomplete=one-time-code inputmode=numeric> <input type=submit value=Enable></form>'
+  END
+  EXEC admin.Trailer()
 END
 GO
 
-INSERT INTO [login].[user](Id,[Name],[HashedPassword]) VALUES 
-GO
+CREATE FN [login].[/login-unlock]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
 
---############################################
-CREATE SCHEMA [timed]
-GO
+  DECLARE unlock int SET unlock = PARSEINT( web.Form('unlock') )
+  IF unlock > 0
+    UPDATE login.user SET FailCount = 0, LockedUntil = 0 WHERE Id = unlock
 
-CREATE TABLE [timed].[Job]([fn] string,[at] int) 
+  EXEC admin.Head( 'Failed Logins' )
+  DECLARE now int SET now = date.Ticks()
+  SELECT '<table><tr><th>User<th>Failed attempts<th>Locked until<th></tr>'
+  SELECT '<tr><td>' | web.Encode( Name ) 
+     | '<td>' | FailCount
+     | '<td>' | CASE WHEN LockedUntil > now THEN date.MicroSecToString( LockedUntil ) ELSE '' END
+     | '<td><form method=post>' | CSRFFIELD() | '<input type=hidden name=unlock value=' | Id | '><input type=submit value=Unlock></form>'
+     | '</tr>'
+  FROM login.user WHERE FailCount > 0 ORDER BY Name
+  SELECT '</table>'
+  EXEC admin.Trailer()
+END
 GO
 
-CREATE FN [timed].[Run]() AS 
-/* 
-  This function is called by the Rust program.
-  The time interval (in milliseconds) is set using the built-in SLEEP function.
-*/
+CREATE FN [login].[/login-usage]() AS 
 BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
 
-  DECLARE now int SET now = date.Ticks()
-
-  DECLARE f string, a int
-  FOR f = fn, a = at FROM timed.Job
+  -- Reset usage ( see DOSRESET ), * means all users.
+  DECLARE reset string SET reset = web.Form('reset')
+  DECLARE n int
+  IF reset != '' SET n = DOSRESET( CASE WHEN reset = '*' THEN '' ELSE reset END )
+
+  EXEC admin.Head( 'Usage' )
+  SELECT '<p>Usage of denial of service limits by each user or IP address, highest percentage used first ( see DOSINFO ).'
+    | '<form method=post>' | CSRFFIELD() | '<input type=hidden name=reset value=*><input type=submit value="Reset all"></form>'
+  SELECT '<table><tr><th>User<th>Requests<th>Read<th>CPU<th>Write<th>Memory<th>Used<th>Failed logins<th>Retry after<th></tr>'
+  DECLARE list string, e string, uid string, name string, used string, lim string, retry string, i int, k int
+  SET list = DOSINFO()
+  WHILE i < 500
   BEGIN
-    IF now >= a
-      EXECUTE( 'EXEC ' | f | '()' )
+    SET e = JSONGET( list, '' | i )
+    IF e = '' BREAK
+    SET uid = JSONGET( e, 'uid' ), used = JSONGET( e, 'used' ), lim = JSONGET( e, 'limit' ), retry = JSONGET( e, 'retry' )
+    SET name = uid
+    IF SUBSTRING( uid, 1, 1 ) = 'u' SET name = Name FROM login.user WHERE Id = PARSEINT( SUBSTRING( uid, 2, LEN( uid ) - 1 ) )
+    SELECT '<tr><td>' | web.Encode( name )
+    SET k = 0
+    WHILE k < 5
+    BEGIN
+      SELECT '<td>' | JSONGET( used, '' | k ) | ' / ' | JSONGET( lim, '' | k )
+      SET k = k + 1
+    END
+    SELECT '<td>' | JSONGET( e, 'pct' ) | '%'
+      | '<td>' | JSONGET( e, 'fails' )
+      | '<td>' | CASE WHEN retry = '0' THEN '' ELSE retry | ' sec' END
+      | '<td><form method=post>' | CSRFFIELD() | '<input type=hidden name=reset value="' | web.Encode( uid ) | '"><input type=submit value=Reset></form>'
+      | '</tr>'
+    SET i = i + 1
   END
-   
-  EXEC timed.Sleep()
+  SELECT '</table>'
+  EXEC admin.Trailer()
 END
 GO
 
-CREATE FN [timed].[Sleep]() AS 
-BEGIN 
-  /* Set sleep time based on timed.Job table */
-
-  DECLARE now int, next int
-  SET now = date.Ticks()
-
-  SET next = now + 24 * 3600 * 1000000 -- 24 hours
+CREATE FN [login].[Alert]( msg string ) AS
+BEGIN
+  /* Called when an account is locked ( see login.LoginFailed ), or an IP address is blocked after too many failed logins ( see LOGINFAIL ). 
+     Edit this function to send an alert, for example by email. */
+END
+GO
 
-  DECLARE t int
-  FOR t = at FROM timed.Job
+CREATE FN [login].[Allowed]( uid int, role int ) RETURNS int AS
+BEGIN
+  /* Check whether the user may access the current page. 
+     Either the user has the role required by the page, or login.Permission grants one of the user's roles access to the page path.
+     If the request uses an API token, the role must also be in the token scopes ( see login.InScope ). */
+  IF login.HasRole( uid, role ) = 1 AND login.InScope( role ) = 1 RETURN 1
+  DECLARE path string SET path = web.Path()
+  DECLARE r int
+  FOR r = Role FROM login.Permission WHERE Path = path
   BEGIN
-    IF t < next SET next = t
+    IF login.HasRole( uid, r ) = 1 AND login.InScope( r ) = 1 RETURN 1
   END
-
-  DECLARE dummy int
-  SET dummy = SLEEP( next-now )
+  RETURN 0
 END
 GO
 
-INSERT INTO [timed].[Job](Id,[fn],[at]) VALUES 
+CREATE FN [login].[CheckLogin]( uid int, password string ) RETURNS int AS
+BEGIN
+  /* Check the password for a login attempt, returns 1 if correct. 
+     Failed attempts are counted, the password is not checked while the account is locked ( see login.LoginFailed ). 
+     For an unknown user or a locked account the password is hashed anyway, so the response time is the same. */
+  DECLARE fails int, locked int
+  SET fails = FailCount, locked = LockedUntil FROM login.user WHERE Id = uid
+  DECLARE x int
+  IF uid = 0 OR locked > date.Ticks()
+  BEGIN
+    DECLARE dummy string SET dummy = PWHASH( password )
+    RETURN 0
+  END
+  IF login.CheckPassword( uid, password ) = 0
+  BEGIN
+    SET x = DEFER( 'EXEC login.LoginFailed(' | uid | ')' )
+    RETURN 0
+  END
+  IF fails > 0 SET x = DEFER( 'UPDATE login.user SET FailCount = 0, LockedUntil = 0 WHERE Id = ' | uid )
+  RETURN 1
+END
 GO
 
---############################################
-CREATE SCHEMA [admin]
+CREATE FN [login].[CheckPassword]( uid int, password string ) RETURNS int AS
+BEGIN
+  /* Check the password for the user, returns 1 if correct.
+     The password is hashed again if the hash parameters have changed, or if the hash is a legacy hash ( see login.hash ). */
+  DECLARE phc string, legacy binary
+  SET phc = Password, legacy = HashedPassword FROM login.user WHERE Id = uid
+  IF phc != ''
+  BEGIN
+    IF PWVERIFY( password, phc ) = 0 RETURN 0
+    IF PWNEEDSREHASH( phc ) = 1 EXEC login.SetPassword( uid, password )
+    RETURN 1
+  END
+  IF BINLEN( legacy ) = 0 RETURN 0
+  IF legacy != login.hash( password | uid ) RETURN 0
+  EXEC login.SetPassword( uid, password )
+  RETURN 1
+END
 GO
 
-CREATE FN [admin].[/admin]() AS
+CREATE FN [login].[Forbidden]() AS
 BEGIN
-   DECLARE cu int SET cu = login.get(0) IF cu = 0 RETURN
-
-   EXEC admin.Head('Admin Home')
+  DECLARE x int SET x = STATUSCODE( 403 )
+  EXEC admin.Head( 'Forbidden' )
+  SELECT '<p>You do not have permission to access this page.'
+  EXEC admin.Trailer()
+END
+GO
 
-   SELECT '
-<p><a target=_blank href="/">Domain Home</a>
-<p><a href=/admin-Execute>Execute SQL</a>
-<p><a href=/browse-Table?s=login&n=user>Logins</a>
-<p><a href=/browse-Table?s=web&n=File>Files</a>
-<p><a target=_blank href=/admin-ScriptAll?option=0>Script All</a> 
-  | <a target=_blank href=/admin-ScriptAll?option=1>Script (no data)</a> 
-  | <a target=_blank href=/admin-ScriptSystem>Script System</a>    
-  | <a target=_blank href=/log-getall>Exact</a>
-<p><a href=/admin-CheckAll>Check all functions compile ok</a> 
-<h3>Schemas</h3>'
+CREATE FN [login].[HasRole]( uid int, role int ) RETURNS int AS
+BEGIN
+  /* Role 0 is any logged in user. Role 1 ( Admin ) implies all other roles. */
+  IF role = 0 RETURN 1
+  DECLARE r int
+  FOR r = Role FROM login.UserRole WHERE User = uid
+  BEGIN
+    IF r = role OR r = 1 RETURN 1
+  END
+  RETURN 0
+END
+GO
 
-   SELECT '<a href=/admin-Schema?s=' | Name | '>' | Name | '</a> | ' FROM sys.Schema ORDER BY Name
+CREATE FN [login].[InScope]( role int ) RETURNS int AS
+BEGIN
+  /* Check the role is allowed by the scopes of the API token ( or the scope claim of the JWT ) used for the request ( if any ).
+     Scopes are role names separated by spaces, blank means all roles. The Admin scope implies all other roles. */
+  IF role = 0 RETURN 1
+  DECLARE bearer string SET bearer = web.Bearer()
+  IF bearer = '' RETURN 1
+  DECLARE scopes string 
+  IF CONTAINS( bearer, '.' ) >= 0 SET scopes = JSONGET( login.JwtClaims(), 'scope' )
+  ELSE SET scopes = Scopes FROM login.Token WHERE TokenHash = SHA(bearer)
+  IF scopes = '' RETURN 1
+  SET scopes = ' ' | REPLACE( scopes, ',', ' ' ) | ' '
+  IF CONTAINS( scopes, ' Admin ' ) >= 0 RETURN 1
+  IF CONTAINS( scopes, ' ' | login.RoleName( role ) | ' ' ) >= 0 RETURN 1
+  RETURN 0
+END
+GO
 
-   EXEC admin.Trailer()
+CREATE FN [login].[InsertOidcLink]( p int, sub string, name string, hash binary ) AS
+BEGIN
+  /* Create a login with the given name, link the subject to it and insert session ( see login.OidcLogin ). 
+     Nothing is done if a login with the name has been created meanwhile, as it is only linked if LinkUsers is set for the provider. */
+  DECLARE uid int SET uid = User FROM login.OidcLink WHERE Provider = p AND Subject = sub
+  IF uid = 0
+  BEGIN
+    SET uid = Id FROM login.user WHERE Name = name
+    IF uid > 0 RETURN
+    INSERT INTO login.user( Name ) VALUES ( name )
+    SET uid = LASTID()
+    INSERT INTO login.OidcLink( Provider, Subject, User ) VALUES ( p, sub, uid )
+  END
+  EXEC login.InsertSession( uid, hash )
 END
 GO
 
-CREATE FN [admin].[/admin-CheckAll]() AS 
+CREATE FN [login].[InsertSession]( uid int, hash binary ) AS
 BEGIN
-  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+  DECLARE now int SET now = date.Ticks()
+  INSERT INTO login.Session( User, TokenHash, Created, Expires ) 
+  VALUES ( uid, hash, now, now + login.SessionLife() * 1000000 )
+  DELETE FROM login.Session WHERE User = uid AND Expires < now
+END
+GO
 
-  EXEC admin.Head('Check All Functions compile')
-  DECLARE sid int, sname string, fname string, err int, n int
+CREATE FN [login].[JwtClaims]() RETURNS string AS
+BEGIN
+  /* Get the claims ( JSON ) of the JWT in the Authorization: Bearer header, blank if it is not valid.
+     The token must be signed by one of the issuers in login.JwtIssuer ( and have the issuer iss claim, unless Issuer is blank ). */
+  DECLARE token string SET token = web.Bearer()
+  DECLARE claims string, iss string
+  FOR claims = JWTVERIFY( token, Alg, Key, Audience ), iss = Issuer FROM login.JwtIssuer
+  BEGIN
+    IF claims != '' AND ( iss = '' OR JSONGET( claims, 'iss' ) = iss ) RETURN claims
+  END
+  RETURN ''
+END
+GO
 
-  FOR sid = Id, sname = sys.QuoteName(Name) FROM sys.Schema
+CREATE FN [login].[JwtUser]() RETURNS int AS
+BEGIN
+  /* Get the user for the JWT in the Authorization: Bearer header, 0 if the token is not valid.
+     The user is the login whose name is the UserClaim claim ( default sub ) of the issuer. */
+  DECLARE token string SET token = web.Bearer()
+  DECLARE claims string, iss string, uclaim string, name string
+  FOR claims = JWTVERIFY( token, Alg, Key, Audience ), iss = Issuer, uclaim = UserClaim FROM login.JwtIssuer
   BEGIN
-    FOR fname = sys.QuoteName(Name) FROM sys.Function WHERE Schema = sid
+    IF claims != '' AND ( iss = '' OR JSONGET( claims, 'iss' ) = iss ) 
+    BEGIN
+      SET name = JSONGET( claims, CASE WHEN uclaim = '' THEN 'sub' ELSE uclaim END )
+      BREAK
+    END
+  END
+  IF name = '' RETURN 0
+  DECLARE uid int SET uid = Id FROM login.user WHERE Name = name
+  IF uid = 0 RETURN 0
+  IF web.SetDos(uid) = 0 RETURN 0
+  RETURN uid
+END
+GO
+
+CREATE FN [login].[LoginFailed]( uid int ) AS
+BEGIN
+  /* Count a failed login for the account. After login.MaxFails() failures in a row the account is locked for a minute, 
+     the time doubling with each further failure ( up to a day ). */
+  DECLARE fails int, locked int
+  SET fails = FailCount + 1, locked = LockedUntil FROM login.user WHERE Id = uid
+  IF fails >= login.MaxFails()
+  BEGIN
+    DECLARE secs int SET secs = 60
+    DECLARE i int SET i = login.MaxFails()
+    WHILE i < fails AND secs < 86400
+    BEGIN
+      SET secs = secs * 2
+      SET i = i + 1
+    END
+    IF secs > 86400 SET secs = 86400
+    SET locked = date.Ticks() + secs * 1000000
+    EXEC login.Alert( 'Login for user ' | login.UserName(uid) | ' locked for ' | secs | ' seconds after ' | fails | ' failed attempts' )
+  END
+  UPDATE login.user SET FailCount = fails, LockedUntil = locked WHERE Id = uid
+END
+GO
+
+CREATE FN [login].[MaxFails]() RETURNS int AS
+BEGIN
+  /* Number of failed logins in a row before an account is locked. */
+  RETURN 5
+END
+GO
+
+CREATE FN [login].[NewSession]( uid int ) AS
+BEGIN
+  /* Start a new session for the user. The token is only stored as a hash. 
+     The row is inserted after the current transaction, as the transaction may be read-only. */
+  DECLARE token string SET token = NEWTOKEN()
+  DECLARE x int 
+  SET x = DEFER( 'EXEC login.InsertSession(' | uid | ',' | SHA(token) | ')' )
+  SET x = SETCOOKIE( 'sid', token, login.SessionLife(), '', 0 )
+END
+GO
+
+CREATE FN [login].[OidcLogin]( p int, claims string ) RETURNS int AS
+BEGIN
+  /* Start a session for the user identified by the ID token claims. Returns 0 if there is no login for the user,
+     or -1 if a TOTP code is needed to complete the login ( see login.TotpLogin ).
+     A subject not seen before is linked to a new login whose name is the UserClaim claim ( default email ), if CreateUsers is set for the provider.
+     It is only linked to an existing login with that name if LinkUsers is set for the provider, 
+     otherwise an administrator must approve the link by adding it to login.OidcLink. */
+  DECLARE sub string SET sub = JSONGET( claims, 'sub' )
+  IF sub = '' RETURN 0
+  DECLARE uid int SET uid = User FROM login.OidcLink WHERE Provider = p AND Subject = sub
+  IF uid > 0
+  BEGIN
+    IF login.TotpPending( uid ) = 1 RETURN -1
+    EXEC login.NewSession( uid )
+    RETURN uid
+  END
+
+  DECLARE claim string, autocreate int, link int
+  SET claim = UserClaim, autocreate = CreateUsers, link = LinkUsers FROM login.OidcProvider WHERE Id = p
+  IF claim = '' SET claim = 'email'
+  IF claim = 'email' AND JSONGET( claims, 'email_verified' ) != 'true' RETURN 0
+  DECLARE name string SET name = JSONGET( claims, claim )
+  IF name = '' RETURN 0
+  SET uid = Id FROM login.user WHERE Name = name
+
+  -- The link ( and login ) is inserted after the current transaction, as the transaction may be read-only.
+  DECLARE x int 
+  IF uid > 0
+  BEGIN
+    IF link = 0 RETURN 0
+    SET x = DEFER( 'INSERT INTO login.OidcLink( Provider, Subject, User ) VALUES (' | p | ',' | sys.SingleQuote( sub ) | ',' | uid | ')' )
+    IF login.TotpPending( uid ) = 1 RETURN -1
+    EXEC login.NewSession( uid )
+    RETURN uid
+  END
+  IF autocreate = 0 RETURN 0
+  DECLARE token string SET token = NEWTOKEN()
+  SET x = DEFER( 'EXEC login.InsertOidcLink(' | p | ',' | sys.SingleQuote( sub ) | ',' | sys.SingleQuote( name ) | ',' | SHA(token) | ')' )
+  SET x = SETCOOKIE( 'sid', token, login.SessionLife(), '', 0 )
+  RETURN 1
+END
+GO
+
+CREATE FN [login].[OidcProviderName]( id int ) RETURNS string AS
+BEGIN
+  SET result = Name FROM login.OidcProvider WHERE Id = id
+END
+GO
+
+CREATE FN [login].[OidcProviderSelect]( colId int, sel int ) RETURNS string AS
+BEGIN
+  DECLARE col string SET col = Name FROM sys.Column WHERE Id = colId
+
+  DECLARE opt string, options string
+
+  FOR opt = '<option ' | CASE WHEN Id = sel THEN ' selected' ELSE '' END 
+  | ' value=' | Id | '>' | web.Encode( Name ) | '</option>'
+  FROM login.OidcProvider
+  ORDER BY Name
+  SET options |= opt
+
+  RETURN '<select id="' | col | '" name="' | col | '">' | options 
+    | '<option ' | CASE WHEN sel = 0 THEN ' selected' ELSE '' END | ' value=0></option>'
+    | '</select>'
+END
+GO
+
+CREATE FN [login].[RenewSession]( id int ) AS
+BEGIN
+  UPDATE login.Session SET Expires = date.Ticks() + login.SessionLife() * 1000000 WHERE Id = id
+END
+GO
+
+CREATE FN [login].[RoleName]( id int ) RETURNS string AS
+BEGIN
+  SET result = Name FROM login.Role WHERE Id = id
+END
+GO
+
+CREATE FN [login].[RoleSelect]( colId int, sel int ) RETURNS string AS
+BEGIN
+  DECLARE col string SET col = Name FROM sys.Column WHERE Id = colId
+
+  DECLARE opt string, options string
+
+  FOR opt = '<option ' | CASE WHEN Id = sel THEN ' selected' ELSE '' END 
+  | ' value=' | Id | '>' | web.Encode( Name ) | '</option>'
+  FROM login.Role
+  ORDER BY Name
+  SET options |= opt
+
+  RETURN '<select id="' | col | '" name="' | col | '">' | options 
+    | '<option ' | CASE WHEN sel = 0 THEN ' selected' ELSE '' END | ' value=0></option>'
+    | '</select>'
+END
+GO
+
+CREATE FN [login].[SessionLife]() RETURNS int AS
+BEGIN
+  /* Session lifetime in seconds. Sessions are renewed when less than half the lifetime remains. */
+  RETURN 30 * 24 * 3600 -- 30 days
+END
+GO
+
+CREATE FN [login].[SetPassword]( uid int, password string ) AS
+BEGIN
+  /* Replace the password hash. The update is made after the current transaction, as the transaction may be read-only. */
+  DECLARE x int 
+  SET x = DEFER( 'UPDATE login.user SET Password = ' | sys.SingleQuote( PWHASH( password ) ) | ', HashedPassword = 0x WHERE Id = ' | uid )
+END
+GO
+
+CREATE FN [login].[TokenUser]() RETURNS int AS
+BEGIN
+  /* Get the user for the API token ( Authorization: Bearer header ), 0 if the token is not valid. */
+  DECLARE id int, uid int, expires int, lastused int, revoked int
+  SET id = Id, uid = User, expires = Expires, lastused = LastUsed, revoked = Revoked 
+  FROM login.Token WHERE TokenHash = SHA( web.Bearer() )
+  DECLARE now int SET now = date.Ticks()
+  IF id = 0 OR revoked > 0 OR ( expires > 0 AND expires < now ) RETURN 0
+
+  -- Record when the token was last used ( at most once a minute ).
+  IF now - lastused > 60000000
+  BEGIN
+    DECLARE x int SET x = DEFER( 'UPDATE login.Token SET LastUsed = ' | now | ' WHERE Id = ' | id )
+  END
+
+  IF web.SetDos(uid) = 0 RETURN 0
+  RETURN uid
+END
+GO
+
+CREATE FN [login].[TotpLogin]() RETURNS int AS
+BEGIN
+  /* Second login step. Check the TOTP code for the user whose password has been checked ( see login.user ). 
+     Returns -1 if the code is not valid. */
+  DECLARE pending string SET pending = COOKIE( 'pending', 32 )
+  DECLARE i int SET i = CONTAINS( pending, ' ' )
+  IF i < 0 RETURN 0
+  DECLARE uid int SET uid = PARSEINT( SUBSTRING( pending, 1, i ) )
+  IF PARSEINT( SUBSTRING( pending, i + 2, 20 ) ) < date.Ticks() RETURN 0
+
+  -- Failed attempts are counted, the code is not checked while the account is locked ( as for login.CheckLogin ).
+  DECLARE f int, fails int, locked int
+  SET fails = FailCount, locked = LockedUntil FROM login.user WHERE Id = uid
+  IF locked > date.Ticks()
+  BEGIN
+    SET f = LOGINFAIL()
+    RETURN -1
+  END
+
+  DECLARE id int, secret string, last int
+  SET id = Id, secret = Secret, last = LastStep FROM login.Totp WHERE User = uid
+  DECLARE step int SET step = TOTPVERIFY( secret, web.Form('totp'), last )
+  IF step = 0 
+  BEGIN
+    SET f = LOGINFAIL()
+    SET f = DEFER( 'EXEC login.LoginFailed(' | uid | ')' )
+    RETURN -1
+  END
+
+  -- Record the time step, so the code cannot be used again.
+  UPDATE login.Totp SET LastStep = step WHERE Id = id
+  IF fails > 0 SET f = DEFER( 'UPDATE login.user SET FailCount = 0, LockedUntil = 0 WHERE Id = ' | uid )
+  DECLARE x int SET x = SETCOOKIE( 'pending', '', 0, '', 0 )
+  EXEC login.NewSession( uid )
+  IF web.SetDos(uid) = 0 RETURN 0
+  RETURN uid
+END
+GO
+
+CREATE FN [login].[TotpPending]( uid int ) RETURNS int AS
+BEGIN
+  /* If the user has TOTP enabled, remember the user ( for 5 minutes ) in an encrypted cookie until the code is entered 
+     ( see login.TotpLogin ) and return 1. The password ( or identity provider login ) has already been checked. */
+  DECLARE totp int SET totp = Id FROM login.Totp WHERE User = uid
+  IF totp = 0 RETURN 0
+  DECLARE x int SET x = SETCOOKIE( 'pending', '' | uid | ' ' | ( date.Ticks() + 300000000 ), 300, '', 32 )
+  RETURN 1
+END
+GO
+
+CREATE FN [login].[Update]( old string, new string ) RETURNS string AS
+BEGIN
+   RETURN
+   CASE 
+   WHEN new = '' THEN old
+   ELSE PWHASH( new )
+   END
+END
+GO
+
+CREATE FN [login].[UserName]( id int ) RETURNS string AS
+BEGIN
+  SET result = Name FROM login.user WHERE Id = id
+END
+GO
+
+CREATE FN [login].[UserSelect]( colId int, sel int ) RETURNS string AS
+BEGIN
+  DECLARE col string SET col = Name FROM sys.Column WHERE Id = colId
+
+  DECLARE opt string, options string
+
+  FOR opt = '<option ' | CASE WHEN Id = sel THEN ' selected' ELSE '' END 
+  | ' value=' | Id | '>' | web.Encode( Name ) | '</option>'
+  FROM login.user
+  ORDER BY Name
+  SET options |= opt
+
+  RETURN '<select id="' | col | '" name="' | col | '">' | options 
+    | '<option ' | CASE WHEN sel = 0 THEN ' selected' ELSE '' END | ' value=0></option>'
+    | '</select>'
+END
+GO
+
+CREATE FN [login].[get]( role int ) RETURNS int AS
+BEGIN
+  /* Get the current logged in user, if none, output login form ( or form for TOTP code ). 
+     If the user does not have the role ( see login.Allowed ), output 403 Forbidden page and return 0. */
+
+  /*
+     Login is initially disabled. Remove or comment out the line below enable Login after Login password has been setup for some user.
+  */
+  RETURN 1 -- Login disabled.
+
+  DECLARE uid int
+  SET uid = login.user()
+  IF uid < 0
+  BEGIN
+    EXEC admin.Head( 'Login' )
+    IF web.Form('totp') != '' SELECT '<p>Invalid code.'
+    SELECT '<form method=post>' | CSRFFIELD() | 'Authentication code <input name=totp autocomplete=one-time-code inputmode=numeric autofocus><br><input type=submit value=Login></form>'
+    EXEC admin.Trailer()
+    RETURN 0
+  END
+  IF uid = 0
+  BEGIN
+    EXEC admin.Head( 'Login' )
+    SELECT '<form method=post>' | CSRFFIELD() | 'User Name <input name=username><br>Password <input type=password name=password><br><input type=submit value=Login></form>'
+    SELECT '<p><a href="/login-oidc?p=' | Id | '">Sign in with ' | web.Encode( Name ) | '</a>' FROM login.OidcProvider ORDER BY Name
+    EXEC admin.Trailer()
+    RETURN 0
+  END
+  IF login.Allowed( uid, role ) = 0
+  BEGIN
+    EXEC login.Forbidden()
+    RETURN 0
+  END
+  RETURN uid
+END
+GO
+
+CREATE FN [login].[hash](s string) RETURNS binary AS
+BEGIN
+  /* Legacy password hash ( HashedPassword column ), only used to check passwords set before PHC hashes were introduced. */
+  SET result = ARGON(s,'Sep 14 2022 saltiness')
+END
+GO
+
+CREATE FN [login].[session]() RETURNS int AS
+BEGIN
+  /* Get the user for the session cookie ( or API token or JWT ), 0 if there is no valid session. */
+  DECLARE bearer string SET bearer = web.Bearer()
+  IF CONTAINS( bearer, '.' ) >= 0 RETURN login.JwtUser()
+  IF bearer != '' RETURN login.TokenUser()
+  DECLARE sid string SET sid = web.Cookie('sid')
+  IF sid = '' RETURN 0
+
+  DECLARE id int, uid int, expires int
+  SET id = Id, uid = User, expires = Expires FROM login.Session WHERE TokenHash = SHA(sid)
+  DECLARE now int SET now = date.Ticks()
+  IF id = 0 OR expires < now RETURN 0
+
+  -- Sliding renewal.
+  DECLARE life int SET life = login.SessionLife()
+  IF expires - now < life * 500000
+  BEGIN
+    DECLARE x int 
+    SET x = DEFER( 'EXEC login.RenewSession(' | id | ')' )
+    SET x = SETCOOKIE( 'sid', sid, life, '', 0 )
+  END
+
+  IF web.SetDos(uid) = 0 RETURN 0
+  RETURN uid
+END
+GO
+
+CREATE FN [login].[user]() RETURNS int AS
+BEGIN
+  /* Returns the logged in user, 0 if none, or -1 if a TOTP code is needed to complete the login. */
+  DECLARE username string SET username = web.Form('username')
+
+  IF username != ''
+  BEGIN
+    DECLARE password string SET password = web.Form('password')
+    DECLARE uid int SET uid = Id FROM login.user WHERE Name = username
+    IF login.CheckLogin( uid, password ) = 0 
+    BEGIN
+      DECLARE f int SET f = LOGINFAIL()
+      SET uid = 0
+    END
+    IF uid > 0
+    BEGIN
+      IF login.TotpPending( uid ) = 1 RETURN -1
+      EXEC login.NewSession( uid )
+      IF web.SetDos(uid) = 0 RETURN 0
+      RETURN uid
+    END
+  END
+  IF web.Cookie('pending') = '' RETURN login.session()
+  IF web.Form('totp') != '' RETURN login.TotpLogin()
+  -- A login ( e.g. with an identity provider ) is waiting for a TOTP code.
+  DECLARE cu int SET cu = login.session()
+  IF cu = 0 RETURN -1
+  RETURN cu
+END
+GO
+
+INSERT INTO [login].[IpRule](Id,[Network],[Path],[Allow]) VALUES 
+GO
+
+INSERT INTO [login].[JwtIssuer](Id,[Name],[Issuer],[Alg],[Key],[Audience],[UserClaim]) VALUES 
+GO
+
+INSERT INTO [login].[OidcLink](Id,[Provider],[Subject],[User]) VALUES 
+GO
+
+INSERT INTO [login].[OidcProvider](Id,[Name],[Issuer],[ClientId],[ClientSecret],[RedirectUri],[Scopes],[UserClaim],[CreateUsers],[LinkUsers]) VALUES 
+GO
+
+INSERT INTO [login].[PathCost](Id,[Path],[Cost]) VALUES 
+GO
+
+INSERT INTO [login].[Permission](Id,[Role],[Path]) VALUES 
+GO
+
+INSERT INTO [login].[Role](Id,[Name],[Description]) VALUES 
+(1,'Admin','Full access, implies all other roles')
+(2,'Replicate','Fetch the transaction log for replication')
+GO
+
+INSERT INTO [login].[Session](Id,[User],[TokenHash],[Created],[Expires]) VALUES 
+GO
+
+INSERT INTO [login].[Token](Id,[User],[Name],[TokenHash],[Scopes],[Created],[Expires],[LastUsed],[Revoked]) VALUES 
+GO
+
+INSERT INTO [login].[Totp](Id,[User],[Secret],[LastStep]) VALUES 
+GO
+
+INSERT INTO [login].[UserRole](Id,[User],[Role]) VALUES 
+GO
+
+INSERT INTO [login].[user](Id,[Name],[HashedPassword],[Password],[FailCount],[LockedUntil]) VALUES 
+GO
+
+--############################################
+CREATE SCHEMA [timed]
+GO
+
+CREATE TABLE [timed].[Job]([fn] string,[at] int) 
+GO
+
+CREATE FN [timed].[Run]() AS 
+/* 
+  This function is called by the Rust program.
+  The time interval (in milliseconds) is set using the built-in SLEEP function.
+*/
+BEGIN 
+
+  DECLARE now int SET now = date.Ticks()
+
+  DECLARE f string, a int
+  FOR f = fn, a = at FROM timed.Job
+  BEGIN
+    IF now >= a
+      EXECUTE( 'EXEC ' | f | '()' )
+  END
+   
+  EXEC timed.Sleep()
+END
+GO
+
+CREATE FN [timed].[Sleep]() AS 
+BEGIN 
+  /* Set sleep time based on timed.Job table */
+
+  DECLARE now int, next int
+  SET now = date.Ticks()
+
+  SET next = now + 24 * 3600 * 1000000 -- 24 hours
+
+  DECLARE t int
+  FOR t = at FROM timed.Job
+  BEGIN
+    IF t < next SET next = t
+  END
+
+  DECLARE dummy int
+  SET dummy = SLEEP( next-now )
+END
+GO
+
+INSERT INTO [timed].[Job](Id,[fn],[at]) VALUES 
+GO
+
+--############################################
+CREATE SCHEMA [admin]
+GO
+
+CREATE FN [admin].[/admin]() AS
+BEGIN
+   DECLARE cu int SET cu = login.get(0) IF cu = 0 RETURN
+
+   EXEC admin.Head('Admin Home')
+
+   SELECT '
+<p><a target=_blank href="/">Domain Home</a>
+<p><a href=/admin-Execute>Execute SQL</a>
+<p><a href=/browse-Table?s=login&n=user>Logins</a> | <a href=/browse-Table?s=login&n=UserRole>User Roles</a> | <a href=/browse-Table?s=login&n=Role>Roles</a> | <a href=/browse-Table?s=login&n=Permission>Permissions</a> | <a href=/browse-Table?s=login&n=PathCost>Page Costs</a> | <a href=/login-sessions>Sessions</a> | <a href=/login-tokens>API Tokens</a> | <a href=/login-totp>Two-factor authentication</a> | <a href=/login-unlock>Failed Logins</a> | <a href=/login-usage>Usage</a> | <a href=/browse-Table?s=login&n=IpRule>IP Rules</a> | <a href=/browse-Table?s=login&n=OidcProvider>Identity Providers</a> | <a href=/browse-Table?s=login&n=JwtIssuer>JWT Issuers</a>
+<p><a href=/audit-Log>Audit Log</a>
+<p><a href=/log-status>Replication</a>
+<p><a href=/browse-Table?s=web&n=File>Files</a>
+<p><a target=_blank href=/admin-ScriptAll?option=0>Script All</a> 
+  | <a target=_blank href=/admin-ScriptAll?option=1>Script (no data)</a> 
+  | <a target=_blank href=/admin-ScriptSystem>Script System</a>    
+  | <a target=_blank href=/log-getall>Exact</a>
+<p><a href=/admin-CheckAll>Check all functions compile ok</a> 
+<h3>Schemas</h3>'
+
+   SELECT '<a href=/admin-Schema?s=' | Name | '>' | Name | '</a> | ' FROM sys.Schema ORDER BY Name
+
+   EXEC admin.Trailer()
+END
+GO
+
+CREATE FN [admin].[/admin-CheckAll]() AS 
+BEGIN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC admin.Head('Check All Functions compile')
+  DECLARE sid int, sname string, fname string, err int, n int
+
+  FOR sid = Id, sname = sys.QuoteName(Name) FROM sys.Schema
+  BEGIN
+    FOR fname = sys.QuoteName(Name) FROM sys.Function WHERE Schema = sid
     BEGIN
       -- SELECT '<br>Checking ' | sname | '.' | fname
       EXECUTE( 'CHECK ' | sname | '.' | fname )
@@ -2809,14 +3662,16 @@ BEGIN
   DECLARE def string, ex string SET def = web.Form('def')
   IF def != '' 
   BEGIN
-    EXECUTE( 'ALTER FN ' | sys.Dot(s,n) | def )
+    DECLARE sql string SET sql = 'ALTER FN ' | sys.Dot(s,n) | def
+    EXECUTE( sql )
     SET ex = EXCEPTION()
+    DECLARE x int SET x = AUDIT( 'AlterFn', sql, ex )
   END
   ELSE SET def = Def FROM sys.Function WHERE Schema = sid AND Name = n 
   EXEC admin.Head( 'Edit ' | n )
   IF ex != '' SELECT '<p>Error: ' | web.Encode( ex )
   SELECT 
-     '<p><form method=post>'
+     '<p><form method=post>' | CSRFFIELD()
      | '<input type=submit value="ALTER"> <a href=/admin-Schema?s=' | s | '>' | s | '</a> . ' | n 
      | CASE WHEN SUBSTRING(n,1,1) = '/' THEN ' <a href=' | n | '>Go</a>' ELSE '' END
      | '<br><textarea name=def rows=40 cols=150>' | web.Encode(def) | '</textarea>' 
@@ -2832,7 +3687,7 @@ BEGIN
   DECLARE sql string SET sql = web.Form('sql')
   EXEC admin.Head( 'Execute' )
   SELECT 
-     '<p><form method=post>'
+     '<p><form method=post>' | CSRFFIELD()
      | 'SQL to <input type=submit value=Execute>'
      | '<br><textarea name=sql rows=20 cols=100' | CASE WHEN sql='' THEN ' placeholder="Enter SQL here. See Manual for details."' ELSE '' END | '>' | web.Encode(sql) | '</textarea>' 
      | '</form>' 
@@ -2842,6 +3697,7 @@ BEGIN
     EXECUTE( sql ) 
     -- EXEC SETMODE( 0 )
     DECLARE ex string SET ex = EXCEPTION()
+    DECLARE x int SET x = AUDIT( 'Execute', sql, ex )
     IF ex != '' SELECT '<p>Error : ' | web.Encode(ex)
   END
   SELECT '<p>Example SQL:'
@@ -3038,6 +3894,7 @@ BEGIN
 
   IF n != '' 
   BEGIN
+    DECLARE x int SET x = AUDIT( 'CreateFn', sys.Dot(s,n), '' )
     EXECUTE( 'CREATE FN ' | sys.Dot(s,n) | '() RETURNS string AS 
 BEGIN
   RETURN ''ToDo''
@@ -3048,7 +3905,7 @@ END
 
   EXEC admin.Head( 'New Function' )
 
-  SELECT '<form method=post><p>New Function Name: <input name=n> <input type=submit value="Ok"></form>' 
+  SELECT '<form method=post>' | CSRFFIELD() | '<p>New Function Name: <input name=n> <input type=submit value="Ok"></form>' 
   EXEC admin.Trailer()
 
 END
@@ -3081,7 +3938,7 @@ GO
 
 CREATE FN [admin].[/admin-ScriptAll]() AS 
 BEGIN 
-  DECLARE cu int SET cu = login.get(0) IF cu = 0 RETURN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
 
   DECLARE option int SET option =  PARSEINT(web.Query('option'))
 
@@ -3101,7 +3958,7 @@ GO
 
 CREATE FN [admin].[/admin-ScriptSchema]() AS BEGIN 
 
-  DECLARE cu int SET cu = login.get(0) IF cu = 0 RETURN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
 
   DECLARE sname string SET sname = web.Query('s')
   DECLARE s int SET s = Id FROM sys.Schema WHERE Name = sname
@@ -3121,7 +3978,7 @@ GO
 
 CREATE FN [admin].[/admin-ScriptSystem]() AS 
 BEGIN 
-  DECLARE cu int SET cu = login.get(0) IF cu = 0 RETURN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
 
   EXEC web.SetContentType( 'text/plain; charset=utf-8' )
 
@@ -3177,15 +4034,31 @@ GO
 CREATE SCHEMA [log]
 GO
 
+CREATE TABLE [log].[Replica]([Name] string,[Acked] int,[Time] int) 
+GO
+
 CREATE TABLE [log].[Status]([Done] int) 
 GO
 
-CREATE TABLE [log].[Transaction]([data] binary) 
+CREATE TABLE [log].[Term]([Term] int,[Fenced] int,[FencedBy] string) 
+GO
+
+CREATE TABLE [log].[Transaction]([data] binary,[Time] int) 
+GO
+
+CREATE FN [log].[/log-forward]() AS 
+BEGIN 
+  -- Runs a request forwarded by a replication server, see Rust replicate::run_forwarded
+  DECLARE cu int SET cu = login.get(2) IF cu = 0 RETURN
+
+  EXEC web.SetContentType( 'application/octet-stream' )
+  DECLARE dummy int SET dummy = LOGFORWARD()
+END
 GO
 
 CREATE FN [log].[/log-get]() AS 
 BEGIN 
-  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+  DECLARE cu int SET cu = login.get(2) IF cu = 0 RETURN
 
   DECLARE k int SET k = PARSEINT( web.Query('k') )
 
@@ -3204,7 +4077,7 @@ GO
 
 CREATE FN [log].[/log-getall]() AS 
 BEGIN 
-  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+  DECLARE cu int SET cu = login.get(2) IF cu = 0 RETURN
 
   EXEC web.SetContentType( 'text/plain; charset=utf-8' )
 
@@ -3214,6 +4087,167 @@ BEGIN
 END
 GO
 
+CREATE FN [log].[/log-promote]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC admin.Head( 'Promote' )
+  DECLARE info string SET info = REPINFO()
+  IF web.Form( '$submit' ) != ''
+  BEGIN
+    IF PROMOTE() = 1 SELECT '<p>Promotion started, see <a href=/log-status>Replication</a>.'
+    ELSE SELECT '<p>Not promoted, this server is not a replication server, or is already being promoted.'
+  END
+  ELSE IF JSONGET( info, 'master' ) = 'true'
+    SELECT '<p>This server is master ( replication term ' | JSONGET( info, 'term' ) | ' ).'
+  ELSE
+  BEGIN
+    SELECT '<p>Promote this replication server to master, if the master has failed. Replication stops, outstanding transactions are applied ( see log.Roll ),'
+      | ' then this server logs transactions, sends emails and runs timed jobs.'
+      | '<p>The old master is fenced when it can be contacted, so it no longer accepts updates.'
+      | ' Other replication servers should be restarted to replicate this server.'
+    SELECT '<form method=post>' | CSRFFIELD() | '<input name="$submit" type=submit value=Promote></form>'
+  END
+  EXEC admin.Trailer()
+END
+GO
+
+CREATE FN [log].[/log-roll]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC admin.Head( 'Roll' )
+  DECLARE done int SET done = log.NextTransaction()
+  SET done = Done FROM log.Status
+  IF web.Form( '$submit' ) != '' EXEC log.Roll()
+  ELSE
+  BEGIN
+    SELECT '<p>' | log.NextTransaction() - done | ' replicated transactions have not been applied to this database.'
+      | ' Apply them ( see log.Roll ).'
+    SELECT '<form method=post>' | CSRFFIELD() | '<input name="$submit" type=submit value=Apply></form>'
+  END
+  EXEC admin.Trailer()
+END
+GO
+
+CREATE FN [log].[/log-status]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC admin.Head( 'Replication' )
+  DECLARE info string, done int, e string, i int, uid string, name string
+  SET info = REPINFO(), done = log.NextTransaction()
+  SET done = Done FROM log.Status
+  SELECT '<p>Replication status ( see REPINFO, also available as <a href=/log-status-json>JSON</a> ).'
+  SELECT '<table>'
+    | '<tr><th align=left>Next transaction<td>' | log.NextTransaction()
+    | '<tr><th align=left>Next to apply ( log.Status.Done )<td>' | done
+    | '<tr><th align=left>Replication term<td>' | JSONGET( info, 'term' )
+  IF JSONGET( info, 'fenced' ) = 'true'
+    SELECT '<tr><th align=left>Fenced<td>Replaced by a promoted replication server, updates are not accepted ( <a href=/log-unfence>Unfence</a> )'
+  IF JSONGET( info, 'connected' ) != ''
+    SELECT '<tr><th align=left>Connected to master<td>' | JSONGET( info, 'connected' )
+      | '<tr><th align=left>Next to fetch<td>' | JSONGET( info, 'fetch' )
+      | '<tr><th align=left>Master next transaction<td>' | JSONGET( info, 'end' )
+      | '<tr><th align=left>Transactions behind<td>' | JSONGET( info, 'behind' )
+      | '<tr><th align=left>Seconds since up to date<td>' | JSONGET( info, 'lag' )
+      | '<tr><th align=left>Seconds since last contact<td>' | JSONGET( info, 'contact' )
+      | '<tr><th align=left>Last error<td>' | web.Encode( JSONGET( info, 'error' ) )
+      | '<tr><th align=left>Apply<td><a href=/log-roll>Apply replicated transactions</a>'
+      | '<tr><th align=left>Promote<td><a href=/log-promote>Promote to master</a>'
+  SELECT '</table><h3>Connected replication servers</h3>'
+  SELECT '<table><tr><th>Name<th>IP address<th>User<th>Connected<th>Sent<th>Acknowledged<th>Behind<th>Last acknowledgement</tr>'
+  WHILE i < 100
+  BEGIN
+    SET e = JSONGET( JSONGET( info, 'replicas' ), '' | i )
+    IF e = '' BREAK
+    SET uid = JSONGET( e, 'uid' )
+    SET name = uid
+    IF SUBSTRING( uid, 1, 1 ) = 'u' SET name = Name FROM login.user WHERE Id = PARSEINT( SUBSTRING( uid, 2, LEN( uid ) - 1 ) )
+    SELECT '<tr><td>' | web.Encode( JSONGET( e, 'name' ) )
+      | '<td>' | web.Encode( JSONGET( e, 'ip' ) )
+      | '<td>' | web.Encode( name )
+      | '<td>' | JSONGET( e, 'connected' ) | ' sec'
+      | '<td>' | JSONGET( e, 'sent' )
+      | '<td>' | JSONGET( e, 'acked' )
+      | '<td>' | JSONGET( e, 'behind' )
+      | '<td>' | JSONGET( e, 'ack' ) | ' sec ago'
+      | '</tr>'
+    SET i = i + 1
+  END
+  SELECT '</table><h3>Registered replication servers</h3>'
+    | '<p>log.Transaction records are kept until every registered replication server has acknowledged them ( see log.Prune ).'
+    | ' <a href=/browse-Table?s=log&n=Replica>Edit</a> to remove a replication server that is no longer used.'
+  SELECT '<table><tr><th>Name<th>Acknowledged<th>Time</tr>'
+  SELECT '<tr><td>' | web.Encode( Name ) | '<td>' | Acked | '<td>' | date.MicroSecToString( Time ) | '</tr>' FROM log.Replica
+  SELECT '</table>'
+  EXEC admin.Trailer()
+END
+GO
+
+CREATE FN [log].[/log-status-json]() AS 
+BEGIN 
+  -- Replication status for monitoring: REPINFO with next ( log.NextTransaction ) and done ( log.Status.Done ) added.
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC web.SetContentType( 'application/json' )
+  DECLARE info string, done int
+  SET info = REPINFO(), done = log.NextTransaction()
+  SET done = Done FROM log.Status
+  SELECT '{"next":' | log.NextTransaction() | ',"done":' | done | ',' | SUBSTRING( info, 2, LEN( info ) - 1 )
+END
+GO
+
+CREATE FN [log].[/log-stream]() AS 
+BEGIN 
+  -- Streams log.Transaction records to a replication server, see Rust replicate::serve
+  DECLARE cu int SET cu = login.get(2) IF cu = 0 RETURN
+
+  DECLARE k int SET k = PARSEINT( web.Query('k') )
+
+  DECLARE dummy int SET dummy = LOGSTREAM( k )
+END
+GO
+
+CREATE FN [log].[/log-unfence]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC admin.Head( 'Unfence' )
+  DECLARE info string SET info = REPINFO()
+  IF web.Form( '$submit' ) != ''
+  BEGIN
+    IF UNFENCE() = 1 
+    BEGIN
+      DECLARE x int SET x = AUDIT( 'Unfence', 'term=' | JSONGET( info, 'term' ), '' )
+      SELECT '<p>This server accepts updates again, see <a href=/log-status>Replication</a>.'
+    END
+    ELSE SELECT '<p>This server is not fenced.'
+  END
+  ELSE IF JSONGET( info, 'fenced' ) != 'true'
+    SELECT '<p>This server is not fenced.'
+  ELSE
+  BEGIN
+    SELECT '<p>This server was fenced by a promoted replication server ( replication term ' | JSONGET( info, 'term' ) | ' ), so it does not accept updates.'
+      | '<p>Only unfence it if the promotion was not intended, and the promoted server has been stopped, otherwise there will be two masters.'
+    SELECT '<form method=post>' | CSRFFIELD() | '<input name="$submit" type=submit value=Unfence></form>'
+  END
+  EXEC admin.Trailer()
+END
+GO
+
+CREATE FN [log].[Ack]( rep string, k int ) AS
+BEGIN
+  -- Called from Rust replicate::serve to save the position acknowledged by a replication server ( see log.Prune ).
+  -- Not logged, so it is not replicated.
+  SELECT NOLOG()
+  DECLARE id int
+  SET id = Id FROM log.Replica WHERE Name = rep
+  IF id = 0 INSERT INTO log.Replica( Name, Acked, Time ) VALUES ( rep, k, date.Ticks() )
+  ELSE UPDATE log.Replica SET Acked = k, Time = date.Ticks() WHERE Id = id
+END
+GO
+
 CREATE FN [log].[GetFetch]() AS 
 BEGIN
   -- Called from Rust tasks::sync_loop
@@ -3230,6 +4264,20 @@ BEGIN
 END
 GO
 
+CREATE FN [log].[KeepDays]() RETURNS int AS
+BEGIN
+  /* Number of days log.Transaction records are kept ( see log.Prune ). */
+  RETURN 7
+END
+GO
+
+CREATE FN [log].[KeepId]() RETURNS int AS
+BEGIN
+  /* log.Transaction records with Id at least this are kept ( see log.Prune ), zero means no minimum. */
+  RETURN 0
+END
+GO
+
 CREATE FN [log].[NextTransaction]() RETURNS int AS 
 BEGIN
   -- Returns Id of next log.Transaction record due to be generated.
@@ -3239,6 +4287,26 @@ BEGIN
 END
 GO
 
+CREATE FN [log].[Prune]( done int ) AS
+BEGIN
+  /* Deletes log.Transaction records with Id less than done that every registered replication server ( log.Replica ) has acknowledged,
+     except records kept by the retention policy ( log.KeepDays and log.KeepId ). 
+     A replication server that is no longer used should be deleted from log.Replica, otherwise records are kept for it.
+     Called every hour by the master ( Rust tasks::prune_loop ) in a transaction that is not logged, so it is not replicated, 
+     and by log.Roll on a replication server, where done is the position applied ( log.Status.Done ). */
+  DECLARE cutoff int SET cutoff = date.Ticks() - log.KeepDays() * 24 * 3600 * 1000000
+  DECLARE lim int SET lim = done
+  DECLARE keep int SET keep = log.KeepId()
+  IF keep > 0 AND keep < lim SET lim = keep
+  DECLARE a int
+  FOR a = Acked FROM log.Replica
+  BEGIN
+    IF a < lim SET lim = a
+  END
+  DELETE FROM log.Transaction WHERE Id < lim AND Time < cutoff
+END
+GO
+
 CREATE FN [log].[Roll]() AS 
 BEGIN
   -- This applies all updates. Updates may need to be limited or filtered in some way.  
@@ -3252,6 +4320,8 @@ BEGIN
     IF DOLOG(d) = 1 BREAK
   END
   UPDATE log.Status SET Done = a WHERE true
+  -- Delete records that have been applied ( see log.Prune ).
+  EXEC log.Prune( a )
   IF a < nt SELECT '<p>Roll incomplete ' | nt - a | ' transactions outstanding'
   ELSE SELECT '<p>Roll complete'
 END
@@ -3261,14 +4331,145 @@ CREATE FN [log].[Save]() AS
 BEGIN
   -- Called from Rust tasks::sync_loop 
   SELECT NOLOG() 
-  INSERT INTO log.Transaction( data ) VALUES ( FILECONTENT(0) )
+  INSERT INTO log.Transaction( data, Time ) VALUES ( FILECONTENT(0), date.Ticks() )
+END
+GO
+
+CREATE FN [log].[SaveBatch]( n int ) AS
+BEGIN
+  -- Called from Rust tasks::backup_stream 
+  SELECT NOLOG() 
+  DECLARE i int
+  WHILE i < n
+  BEGIN
+    INSERT INTO log.Transaction( data, Time ) VALUES ( FILECONTENT(i), date.Ticks() )
+    SET i += 1
+  END
 END
 GO
 
+CREATE FN [log].[SetTerm]( t int, f int, b string ) AS
+BEGIN
+  -- Called from Rust SharedState::save_term when a replication server is promoted, or the server is fenced ( by promotion b ) or unfenced.
+  -- Not logged, so it is not replicated.
+  SELECT NOLOG()
+  DECLARE id int
+  SET id = Id FROM log.Term
+  IF id = 0 INSERT INTO log.Term( Term, Fenced, FencedBy ) VALUES ( t, f, b )
+  ELSE UPDATE log.Term SET Term = t, Fenced = f, FencedBy = b WHERE Id = id
+END
+GO
+
+INSERT INTO [log].[Replica](Id,[Name],[Acked],[Time]) VALUES 
+GO
+
 INSERT INTO [log].[Status](Id,[Done]) VALUES 
 GO
 
-INSERT INTO [log].[Transaction](Id,[data]) VALUES 
+INSERT INTO [log].[Term](Id,[Term],[Fenced],[FencedBy]) VALUES 
+GO
+
+INSERT INTO [log].[Transaction](Id,[data],[Time]) VALUES 
+GO
+
+--############################################
+CREATE SCHEMA [audit]
+GO
+
+CREATE TABLE [audit].[Log]([Time] int,[User] int,[Ip] string,[Path] string,[Action] string,[Detail] string,[Outcome] string,[Hash] binary) 
+GO
+
+CREATE FN [audit].[/audit-Log]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  DECLARE u string SET u = web.Query('u')
+  DECLARE q string SET q = web.Query('q')
+  DECLARE since string SET since = web.Query('since')
+  DECLARE until string SET until = web.Query('until')
+  DECLARE verify string SET verify = web.Query('verify')
+
+  -- Filters : user name ( blank for all ), text in action, path, detail or outcome, and date range.
+  DECLARE uid int SET uid = -1
+  IF u != '' 
+  BEGIN
+    SET uid = -2
+    SET uid = Id FROM login.user WHERE Name = u
+  END
+  DECLARE lo int, hi int
+  SET lo = 0 IF since != '' SET lo = date.StringToDays( since ) * 86400000000
+  SET hi = 9223372036854775807 IF until != '' SET hi = ( date.StringToDays( until ) + 1 ) * 86400000000
+
+  EXEC admin.Head( 'Audit Log' )
+  SELECT '<form>User <input name=u size=10 value="' | web.Encode(u) | '">'
+     | ' Text <input name=q size=20 value="' | web.Encode(q) | '">'
+     | ' From <input name=since size=12 value="' | web.Encode(since) | '">'
+     | ' To <input name=until size=12 value="' | web.Encode(until) | '">'
+     | ' <input type=submit value=Search></form>'
+     | '<p><a href="/audit-Log?verify=1">Verify</a> the log has not been altered.'
+
+  IF verify != '' EXEC audit.Verify()
+
+  SELECT '<table><tr><th>Id<th>Time<th>User<th>IP<th>Path<th>Action<th>Detail<th>Outcome</tr>'
+  DECLARE n int, row string
+  SET n = 0
+  FOR row = '<tr><td>' | Id 
+     | '<td>' | date.MicroSecToString( Time ) 
+     | '<td>' | CASE WHEN User = 0 THEN '' ELSE web.Encode( login.UserName( User ) ) END
+     | '<td>' | web.Encode( Ip )
+     | '<td>' | web.Encode( Path )
+     | '<td>' | web.Encode( Action )
+     | '<td><pre>' | web.Encode( Detail ) | '</pre>'
+     | '<td>' | web.Encode( Outcome )
+     | '</tr>'
+  FROM audit.Log 
+  WHERE ( uid = -1 OR User = uid ) AND Time >= lo AND Time < hi
+    AND ( q = '' OR CONTAINS( Action | ' ' | Path | ' ' | Detail | ' ' | Outcome, q ) >= 0 )
+  ORDER BY Id DESC
+  BEGIN
+    SELECT row
+    SET n += 1
+    IF n = 500 BREAK
+  END
+  SELECT '</table>'
+  IF n = 500 SELECT '<p>Only the latest 500 matching records are shown.'
+  EXEC admin.Trailer()
+END
+GO
+
+CREATE FN [audit].[Verify]() AS 
+BEGIN
+  /* Check each record MAC ( see AUDITVERIFY ), which chains to the previous record, so altered, inserted or deleted records are detected.
+     Deleting the latest records is detected by comparing with the last MAC written since the server started ( see AUDITLAST ). */
+  DECLARE prev binary, id int, expect int, ok int, hash binary, bad int, missing int, n int
+  SET expect = 1, bad = 0, missing = 0, n = 0
+  -- No ORDER BY, as that would evaluate AUDITVERIFY for every record before prev is set. Records are read in Id order.
+  FOR id = Id, hash = Hash, ok = AUDITVERIFY( prev, Time, User, Ip, Path, Action, Detail, Outcome, Hash ) 
+  FROM audit.Log
+  BEGIN
+    IF id != expect
+    BEGIN
+      SELECT '<p>Records ' | expect | ' to ' | ( id - 1 ) | ' are missing.'
+      SET missing += id - expect
+    END
+    IF ok = 0 
+    BEGIN
+      IF bad = 0 SELECT '<p>Record ' | id | ' has been altered, or records before it have been altered or removed.'
+      SET bad += 1
+    END
+    SET prev = hash, expect = id + 1, n += 1
+  END
+  DECLARE last binary SET last = AUDITLAST()
+  IF BINLEN( last ) > 0 AND last != prev
+  BEGIN
+    SELECT '<p>The latest records have been removed or altered.'
+    SET bad += 1
+  END
+  IF bad = 0 AND missing = 0 SELECT '<p>Verified ' | n | ' records ok.'
+END
+GO
+
+INSERT INTO [audit].[Log](Id,[Time],[User],[Ip],[Path],[Action],[Detail],[Outcome],[Hash]) VALUES 
 GO
 
 DECLARE tid int, sid int, cid int, rs int, rt int
@@ -3402,6 +4603,12 @@ GO
 DECLARE tid int, sid int, cid int, rs int, rt int
 SET sid = Id FROM sys.Schema WHERE Name = 'browse'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Table'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Role'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'Role'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','Role required to browse the table, blank means Admin',rt,'',0,0,'','',0)
 GO
 DECLARE tid int, sid int, cid int, rs int, rt int
 SET sid = Id FROM sys.Schema WHERE Name = 'email'
@@ -3477,8 +4684,84 @@ VALUES (tid,'email.SmtpAccountName','email.SmtpAccountSelect','','','',0)
 GO
 DECLARE tid int, sid int, cid int, rs int, rt int
 SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'OidcLink'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Provider'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'OidcProvider'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'','',0)
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'user'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'','',0)
+GO
+DECLARE tid int, sid int, cid int, rs int, rt int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'OidcProvider'
+INSERT INTO browse.Table(Id,NameFunction, SelectFunction, DefaultOrder, Title, Description, Role) 
+VALUES (tid,'login.OidcProviderName','login.OidcProviderSelect','','','',0)
+GO
+DECLARE tid int, sid int, cid int, rs int, rt int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Permission'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Role'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'Role'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'','',0)
+GO
+DECLARE tid int, sid int, cid int, rs int, rt int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Role'
+INSERT INTO browse.Table(Id,NameFunction, SelectFunction, DefaultOrder, Title, Description, Role) 
+VALUES (tid,'login.RoleName','login.RoleSelect','','','',0)
+GO
+DECLARE tid int, sid int, cid int, rs int, rt int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Token'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'user'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'','',0)
+GO
+DECLARE tid int, sid int, cid int, rs int, rt int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Totp'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'user'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'','',0)
+GO
+DECLARE tid int, sid int, cid int, rs int, rt int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'UserRole'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Role'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'Role'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'','',0)
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'user'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'','',0)
+GO
+DECLARE tid int, sid int, cid int, rs int, rt int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'user'
-SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'HashedPassword'
+INSERT INTO browse.Table(Id,NameFunction, SelectFunction, DefaultOrder, Title, Description, Role) 
+VALUES (tid,'login.UserName','login.UserSelect','','','',0)
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Password'
 SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = '' 
 SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = ''
 
@@ -3503,4 +4786,20 @@ DECLARE tid int, sid int, cid int, rs int, rt int
 SET sid = Id FROM sys.Schema WHERE Name = 'log'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Transaction'
 GO
+DECLARE tid int, sid int, cid int, rs int, rt int
+SET sid = Id FROM sys.Schema WHERE Name = 'audit'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Log'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Time'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = '' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = ''
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'browse.InputTime','',3)
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+SET rs = 0 SET rs =Id FROM sys.Schema WHERE Name = 'login' 
+SET rt = 0 SET rt =Id FROM sys.Table WHERE Schema = rs AND Name = 'user'
+
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputRows],[InputFunction],[ChildDisplayFunction],[Datatype]) 
+VALUES (cid, 0,'','',rt,'',0,0,'','',0)
+GO
 "###;
\ No newline at end of file
@@ -0,0 +1,220 @@
+use crate::HashMap;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// Set of networks ( CIDR blocks ), grouped by prefix length so an address is checked with one lookup per length.
+#[derive(Default)]
+struct CidrSet {
+    /// Prefix length ( bits of an IPv6 address, IPv4 addresses are mapped ) and masked networks.
+    by_len: Vec<(u32, HashSet<u128>)>,
+}
+
+impl CidrSet {
+    fn insert(&mut self, net: u128, len: u32) {
+        let net = mask(net, len);
+        match self.by_len.iter_mut().find(|(l, _)| *l == len) {
+            Some((_, set)) => {
+                set.insert(net);
+            }
+            None => self.by_len.push((len, HashSet::from([net]))),
+        }
+    }
+
+    fn contains(&self, ip: u128) -> bool {
+        self.by_len
+            .iter()
+            .any(|(len, set)| set.contains(&mask(ip, *len)))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_len.is_empty()
+    }
+}
+
+/// Rules for a path prefix.
+#[derive(Default)]
+struct PathRules {
+    allow: CidrSet,
+    deny: CidrSet,
+}
+
+/// IP address allow and deny rules ( see login.IpRule ).
+#[derive(Default)]
+pub struct IpRules {
+    /// Rules for each path prefix ( blank means all paths ).
+    paths: HashMap<String, PathRules>,
+}
+
+impl IpRules {
+    /// Load the rules from the login.IpRule table. Rules with an invalid network are ignored.
+    pub fn load(db: &rustdb::DB) -> Self {
+        let mut result = Self::default();
+        if let Some(t) = db.get_table(&rustdb::ObjRef::new("login", "IpRule")) {
+            for (pp, off) in t.scan(db) {
+                let p = &pp.borrow();
+                let a = t.access(p, off);
+                let network = a.str(db, 0);
+                if !result.add(&network, a.str(db, 1), a.int(2) != 0) {
+                    println!("Invalid network in login.IpRule network={network}");
+                }
+            }
+        }
+        result
+    }
+
+    /// Add a rule for a network and path prefix. Result is false if the network is not valid.
+    fn add(&mut self, network: &str, path: String, allow: bool) -> bool {
+        let Some((net, len)) = parse_cidr(network) else {
+            return false;
+        };
+        let rules = self.paths.entry(path).or_default();
+        let set = if allow {
+            &mut rules.allow
+        } else {
+            &mut rules.deny
+        };
+        set.insert(net, len);
+        true
+    }
+
+    /// Whether the login.IpRule table has changes that have not been saved yet.
+    pub fn changed(db: &rustdb::DB) -> bool {
+        db.get_table(&rustdb::ObjRef::new("login", "IpRule"))
+            .is_some_and(|t| t.file.changed())
+    }
+
+    /// Check whether requests to path are allowed from ip. For each path prefix of path that has rules,
+    /// ip must not be denied, and must be allowed if there are allow rules. A blank path only checks rules for all paths.
+    pub fn allowed(&self, ip: &str, path: &str) -> bool {
+        if self.paths.is_empty() {
+            return true;
+        }
+        let ip = ip.parse::<IpAddr>().ok().map(to_u128);
+        for (prefix, rules) in &self.paths {
+            if !path.starts_with(prefix.as_str()) {
+                continue;
+            }
+            let matched = |set: &CidrSet| ip.is_some_and(|ip| set.contains(ip));
+            if matched(&rules.deny) || !rules.allow.is_empty() && !matched(&rules.allow) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Map an IP address to 128 bits ( IPv4 addresses are mapped to ::ffff:a.b.c.d ).
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(a) => u128::from(a.to_ipv6_mapped()),
+        IpAddr::V6(a) => u128::from(a),
+    }
+}
+
+/// Parse a network in CIDR notation, e.g. 192.168.1.0/24 or 2001:db8::/32. A single address has no /length.
+fn parse_cidr(s: &str) -> Option<(u128, u32)> {
+    let s = s.trim();
+    let (addr, len) = match s.split_once('/') {
+        Some((addr, len)) => (addr, Some(len.parse::<u32>().ok()?)),
+        None => (s, None),
+    };
+    let ip = addr.parse::<IpAddr>().ok()?;
+    let (max, offset) = if ip.is_ipv4() { (32, 96) } else { (128, 0) };
+    let len = len.unwrap_or(max);
+    if len > max {
+        return None;
+    }
+    Some((to_u128(ip), len + offset))
+}
+
+/// Keep the first len bits of an address.
+fn mask(ip: u128, len: u32) -> u128 {
+    match len {
+        0 => 0,
+        len => ip & (!0 << (128 - len)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> u128 {
+        to_u128(s.parse().unwrap())
+    }
+
+    fn rules(list: &[(&str, &str, bool)]) -> IpRules {
+        let mut result = IpRules::default();
+        for (network, path, allow) in list {
+            assert!(result.add(network, path.to_string(), *allow));
+        }
+        result
+    }
+
+    #[test]
+    fn parse_ipv4() {
+        assert_eq!(
+            parse_cidr("192.168.1.0/24"),
+            Some((ip("::ffff:192.168.1.0"), 120))
+        );
+        assert_eq!(parse_cidr(" 10.0.0.1 "), Some((ip("::ffff:10.0.0.1"), 128)));
+        assert_eq!(parse_cidr("0.0.0.0/0"), Some((ip("::ffff:0.0.0.0"), 96)));
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("10.0.0.0/"), None);
+        assert_eq!(parse_cidr("10.0.0/8"), None);
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        assert_eq!(parse_cidr("::/0"), Some((0, 0)));
+        assert_eq!(parse_cidr("2001:db8::/32"), Some((ip("2001:db8::"), 32)));
+        assert_eq!(
+            parse_cidr("2001:db8::1/128"),
+            Some((ip("2001:db8::1"), 128))
+        );
+        assert_eq!(parse_cidr("2001:db8::1"), Some((ip("2001:db8::1"), 128)));
+        assert_eq!(parse_cidr("2001:db8::/129"), None);
+    }
+
+    #[test]
+    fn parse_ipv4_mapped() {
+        // An IPv4-mapped IPv6 network is the same as the IPv4 network.
+        assert_eq!(
+            parse_cidr("::ffff:192.168.1.0/120"),
+            parse_cidr("192.168.1.0/24")
+        );
+        assert_eq!(parse_cidr("::ffff:10.0.0.1"), parse_cidr("10.0.0.1"));
+    }
+
+    #[test]
+    fn mask_lengths() {
+        let a = ip("2001:db8:1234:5678::1");
+        assert_eq!(mask(a, 0), 0);
+        assert_eq!(mask(a, 32), ip("2001:db8::"));
+        assert_eq!(mask(a, 64), ip("2001:db8:1234:5678::"));
+        assert_eq!(mask(a, 128), a);
+        assert_eq!(
+            mask(ip("::ffff:192.168.1.77"), 120),
+            ip("::ffff:192.168.1.0")
+        );
+    }
+
+    #[test]
+    fn allowed() {
+        let r = rules(&[
+            ("203.0.113.0/24", "/admin", true),
+            ("2001:db8::/32", "/admin", true),
+            ("198.51.100.7", "", false),
+        ]);
+        assert!(r.allowed("203.0.113.9", "/admin-Execute"));
+        assert!(r.allowed("::ffff:203.0.113.9", "/admin"));
+        assert!(r.allowed("2001:db8:ffff::1", "/admin"));
+        assert!(!r.allowed("203.0.114.1", "/admin"));
+        assert!(!r.allowed("2001:db9::1", "/admin"));
+        assert!(!r.allowed("not an address", "/admin"));
+        assert!(r.allowed("203.0.114.1", "/other"));
+        assert!(!r.allowed("198.51.100.7", "/other"));
+        assert!(!r.allowed("::ffff:198.51.100.7", ""));
+        assert!(IpRules::default().allowed("198.51.100.7", "/admin"));
+    }
+}
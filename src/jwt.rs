@@ -0,0 +1,179 @@
+use crate::crypt::{b64, unb64};
+use aws_lc_rs::{hmac, rand::SystemRandom, signature};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde_json::Value;
+
+/// Allowed clock skew ( in seconds ) when checking exp and nbf claims.
+const LEEWAY: i64 = 60;
+
+/// Decoded JWT ( compact serialisation ).
+pub struct Jwt {
+    /// JOSE header.
+    pub header: Value,
+    /// Claims set.
+    pub claims: Value,
+    /// Signed part of the token ( encoded header and claims ).
+    input: String,
+    /// Signature.
+    sig: Vec<u8>,
+}
+
+impl Jwt {
+    /// Decode a token, without checking the signature.
+    pub fn decode(token: &str) -> Option<Self> {
+        let (input, sig) = token.trim().rsplit_once('.')?;
+        let (header, claims) = input.split_once('.')?;
+        Some(Self {
+            header: serde_json::from_slice(&unb64(header)?).ok()?,
+            claims: serde_json::from_slice(&unb64(claims)?).ok()?,
+            input: input.to_string(),
+            sig: unb64(sig)?,
+        })
+    }
+
+    /// Get the signature algorithm ( alg header ).
+    pub fn alg(&self) -> &str {
+        self.header["alg"].as_str().unwrap_or("")
+    }
+
+    /// Get the key id ( kid header ), blank if none.
+    pub fn kid(&self) -> &str {
+        self.header["kid"].as_str().unwrap_or("")
+    }
+
+    /// Check the token is signed with alg using key, which is the shared secret ( HS256 ),
+    /// or for RS256 and ES256 a public key in PEM format, a JSON Web Key or a JWK Set ( the key is found using the kid header ).
+    pub fn verify(&self, alg: &str, key: &str) -> bool {
+        if self.alg() != alg {
+            return false;
+        }
+        let input = self.input.as_bytes();
+        if alg == "HS256" {
+            let k = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+            return hmac::verify(&k, input, &self.sig).is_ok();
+        }
+        let key = key.trim();
+        if key.starts_with('{') {
+            let Ok(jwk) = serde_json::from_str::<Value>(key) else {
+                return false;
+            };
+            return match jwk["keys"].as_array() {
+                Some(keys) => keys.iter().any(|k| {
+                    (self.kid().is_empty() || k["kid"] == self.kid()) && self.verify_jwk(k)
+                }),
+                None => self.verify_jwk(&jwk),
+            };
+        }
+        let Some(der) = pem(key) else {
+            return false;
+        };
+        let params: &dyn signature::VerificationAlgorithm = match alg {
+            "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+            "ES256" => &signature::ECDSA_P256_SHA256_FIXED,
+            _ => return false,
+        };
+        signature::UnparsedPublicKey::new(params, der)
+            .verify(input, &self.sig)
+            .is_ok()
+    }
+
+    /// Check the signature using a JSON Web Key ( RFC 7517 ). RS256 and ES256 are supported.
+    pub fn verify_jwk(&self, jwk: &Value) -> bool {
+        let field = |name: &str| jwk[name].as_str().and_then(unb64);
+        let input = self.input.as_bytes();
+        match (self.alg(), jwk["kty"].as_str()) {
+            ("RS256", Some("RSA")) => {
+                let (Some(n), Some(e)) = (field("n"), field("e")) else {
+                    return false;
+                };
+                signature::RsaPublicKeyComponents { n, e }
+                    .verify(&signature::RSA_PKCS1_2048_8192_SHA256, input, &self.sig)
+                    .is_ok()
+            }
+            ("ES256", Some("EC")) if jwk["crv"] == "P-256" => {
+                let (Some(x), Some(y)) = (field("x"), field("y")) else {
+                    return false;
+                };
+                let mut point = vec![4];
+                point.extend_from_slice(&x);
+                point.extend_from_slice(&y);
+                signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(input, &self.sig)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Check the token has not expired ( exp claim, required ) and is valid yet ( nbf claim, optional ).
+    pub fn check_time(&self, now_secs: i64) -> bool {
+        let Some(exp) = self.claims["exp"].as_i64() else {
+            return false;
+        };
+        let nbf = self.claims["nbf"].as_i64().unwrap_or(0);
+        now_secs < exp + LEEWAY && nbf <= now_secs + LEEWAY
+    }
+
+    /// Check the audience ( aud claim, a string or array of strings ) includes aud.
+    pub fn check_aud(&self, aud: &str) -> bool {
+        match &self.claims["aud"] {
+            Value::String(s) => s == aud,
+            Value::Array(list) => list.iter().any(|x| x == aud),
+            _ => false,
+        }
+    }
+}
+
+/// Sign claims, result is a compact JWT. key is the shared secret ( HS256 ), or for RS256 and ES256 a PKCS#8 private key in PEM format.
+pub fn sign(alg: &str, key: &str, claims: &Value) -> Result<String, String> {
+    if !claims.is_object() {
+        return Err("claims must be a JSON object".to_string());
+    }
+    let header = serde_json::json!({ "alg": alg, "typ": "JWT" });
+    let input = format!(
+        "{}.{}",
+        b64(header.to_string().as_bytes()),
+        b64(claims.to_string().as_bytes())
+    );
+    let msg = input.as_bytes();
+    let sig = match alg {
+        "HS256" => {
+            let k = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+            hmac::sign(&k, msg).as_ref().to_vec()
+        }
+        "RS256" => {
+            let der = pem(key).ok_or("invalid PEM key")?;
+            let kp = signature::RsaKeyPair::from_pkcs8(&der).map_err(|e| e.to_string())?;
+            let mut sig = vec![0; kp.public_modulus_len()];
+            kp.sign(
+                &signature::RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                msg,
+                &mut sig,
+            )
+            .map_err(|e| e.to_string())?;
+            sig
+        }
+        "ES256" => {
+            let der = pem(key).ok_or("invalid PEM key")?;
+            let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+            let kp = signature::EcdsaKeyPair::from_pkcs8(alg, &der).map_err(|e| e.to_string())?;
+            let sig = kp
+                .sign(&SystemRandom::new(), msg)
+                .map_err(|e| e.to_string())?;
+            sig.as_ref().to_vec()
+        }
+        _ => return Err(format!("unsupported alg {alg}")),
+    };
+    Ok(format!("{input}.{}", b64(&sig)))
+}
+
+/// Decode a key in PEM format ( the base64 lines between the BEGIN and END lines ).
+fn pem(s: &str) -> Option<Vec<u8>> {
+    let b64: String = s
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    STANDARD.decode(b64).ok()
+}
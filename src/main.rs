@@ -1,14 +1,14 @@
 use rustdb::{
-    AtomicFile, BlockPageStg, DB, Database, FastFileStorage, HashMap, Limits, MultiFileStorage,
+    AtomicFile, BasicStorage, BlockPageStg, DB, Database, FastFileStorage, HashMap, Limits, MultiFileStorage,
     ObjRef, PageStorage, SharedPagedData, Value,
     alloc::{LRc, LVec},
 };
 
 use std::sync::{Arc, Mutex};
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{Semaphore, broadcast, mpsc};
 
 #[global_allocator]
-static GLOBAL_ALLOC: rustdb::alloc::Perm = rustdb::alloc::Perm;
+static GLOBAL_ALLOC: meter::Meter = meter::Meter;
 
 // #[global_allocator]
 // static ALLOC: numalloc::NumaAlloc = numalloc::NumaAlloc;
@@ -18,24 +18,39 @@ fn main()
 {
     // Read program arguments.
     let args = Args::parse();
-    let listen = format!("{}:{}", args.ip, args.port);
-    let is_master = args.rep.is_empty();
+    let limits = limits(&args);
 
-    let mut limits = Limits::default();
-    limits.blk_cap = args.blk_cap;
-    limits.page_sizes = args.page_sizes;
-    limits.max_div = args.max_div;
-    limits.af_lim.map_lim = args.map_lim;
-    limits.af_lim.rbuf_mem = args.rbuf_mem;
-    limits.af_lim.swbuf = args.swbuf;
-    limits.af_lim.uwbuf = args.uwbuf;
+    if let Some(Command::Recover(r)) = &args.command {
+        if let Err(e) = recover::run(r, &limits) {
+            println!("Recovery failed error={e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Check the password hashing parameters.
+    let pw_params = match argon2::Params::new(args.pw_mem, args.pw_time, args.pw_par, None) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Invalid password hashing parameters ( --pw-mem, --pw-time, --pw-par ) error={e}");
+            std::process::exit(1);
+        }
+    };
+
+    // Read the server key ( used to sign cookies etc. ).
+    let key = match crypt::load_key("rustweb.key") {
+        Ok(k) => k,
+        Err(e) => {
+            println!("Server key error={e}");
+            std::process::exit(1);
+        }
+    };
+
+    let listen = format!("{}:{}", args.ip, args.port.unwrap());
+    let is_master = args.rep.is_empty();
 
     // Construct BlockPageStg.
-    let file = MultiFileStorage::new("rustweb.rustdb");
-    // let file = atom_file::AnyFileStorage::new("rustweb.rustdb");
-    let upd = FastFileStorage::new("rustweb.upd");
-    let stg = AtomicFile::new_with_limits(file, upd, &limits.af_lim);
-    let ps = BlockPageStg::new(stg, &limits);
+    let ps = page_storage("rustweb.rustdb", FastFileStorage::new("rustweb.upd"), &limits);
     let is_new = ps.is_new();
 
     // SharedPagedData allows for one writer and multiple readers.
@@ -61,16 +76,61 @@ fn main()
         email_tx,
         sleep_tx,
         wait_tx,
-        is_master,
+        master: is_master.into(),
+        fenced: false.into(),
+        fenced_by: Mutex::new(String::new()),
+        term: 0.into(),
+        backup: Mutex::new(None),
+        promote_after: args.promote_after,
         replicate_source: args.rep,
         replicate_credentials: args.login,
-        dos_limit: [args.dos_count, args.dos_read, args.dos_cpu, args.dos_write],
+        replicate_token: rep_token(&args.rep_token_file),
+        replicate_name: args.rep_name,
+        rep_writes: args.rep_writes,
+        rep_client: reqwest::Client::new(),
+        rep_status: Mutex::new(Default::default()),
+        rep_conns: Mutex::new(HashMap::default()),
+        key,
+        pw_params,
+        dos_limit: [
+            args.dos_count,
+            args.dos_read,
+            args.dos_cpu,
+            args.dos_write,
+            args.dos_mem,
+        ],
+        dos_rate: [
+            args.dos_count_rate,
+            args.dos_read_rate,
+            args.dos_cpu_rate,
+            args.dos_write_rate,
+            args.dos_mem_rate,
+        ],
+        dos_subnet: args.dos_subnet,
+        dos_network: args.dos_network,
+        conn_limit: Arc::new(Semaphore::new(permits(args.max_conn))),
+        ip_conn_limit: args.max_conn_ip,
+        proxies: args.proxy,
+        ip_conns: Mutex::new(HashMap::default()),
+        read_limit: Semaphore::new(permits(args.max_readers)),
+        write_limit: Semaphore::new(permits(args.max_writers)),
+        fail_limit: args.dos_fail,
         dos: Mutex::new(HashMap::default()),
+        dos_save: args.dos_save,
+        ip_rules: Mutex::new(Arc::default()),
+        oidc: Mutex::new(HashMap::default()),
+        audit: tokio::sync::Mutex::new(None),
         tracetime: args.tracetime,
         tracedos: args.tracedos,
         tracemem: args.tracemem,
     });
 
+    // Load the replication term, a server that has been fenced stays fenced when restarted.
+    ss.load_term();
+
+    // Load the IP address rules before connections are accepted ( see login.IpRule ).
+    ss.set_ip_rules(&Database::new(spd.new_reader(), "", bmap.clone()));
+
     // let rt = tokio::runtime::Runtime::new().unwrap();
     let rt = tokio::runtime::Builder::new_multi_thread() // new_current_thread()
         .enable_io()
@@ -79,25 +139,38 @@ fn main()
         .unwrap();
 
     rt.block_on(async {
-        if is_master {
-            // Start the task that sends emails
-            let ssc = ss.clone();
-            tokio::spawn(async move { tasks::email_loop(email_rx, ssc).await });
+        // Start the task that sends emails ( only notified when master ).
+        let ssc = ss.clone();
+        tokio::spawn(async move { tasks::email_loop(email_rx, ssc).await });
 
-            // Start the task that calls timed.Run
-            let ssc = ss.clone();
-            tokio::spawn(async move { tasks::sleep_loop(sleep_rx, ssc).await });
-        } else {
-            // Start the database backup task.
+        // Start the task that calls timed.Run ( only when master ).
+        let ssc = ss.clone();
+        tokio::spawn(async move { tasks::sleep_loop(sleep_rx, ssc).await });
+
+        if !is_master {
+            // Start the database backup task, aborted if the server is promoted ( see tasks::promote ).
             let ssc = ss.clone();
-            tokio::spawn(async move { tasks::backup_loop(is_new, ssc).await });
+            let task = tokio::spawn(async move { tasks::backup_loop(is_new, ssc).await });
+            *ss.backup.lock().unwrap() = Some(task.abort_handle());
         }
 
-        // Start the task that regularly decreases usage values.
+        // Start the task that deletes log.Transaction records replicated to every replication server ( only when master ).
+        let ssc = ss.clone();
+        tokio::spawn(async move { tasks::prune_loop(ssc).await });
+
+        // Start the task that regularly refills usage buckets, and removes unused entries.
         let ssc = ss.clone();
         tokio::spawn(async move { tasks::u_decay_loop(ssc).await });
 
+        // Load saved usage information, and start the task that saves it.
+        if ss.dos_save > 0 {
+            ss.u_load(share::DOS_FILE);
+            let ssc = ss.clone();
+            tokio::spawn(async move { tasks::u_save_loop(ssc).await });
+        }
+
         // Start the task that updates the database.
+        let ssc = ss.clone();
         std::thread::spawn(move || {
             // Get write-access to database ( there will only be one writer ).
             let wapd = spd.new_writer();
@@ -110,17 +183,21 @@ fn main()
                 let mut tr = rustdb::GenTransaction::default();
                 db.run(init, &mut tr);
                 db.save();
+                ssc.set_ip_rules(&db);
             }
 
             // Process messages that update the database.
             while let Some(mut sm) = update_rx.blocking_recv() {
-                let sql = sm.trans.x.qy.sql.clone();
-                db.run(&sql, &mut sm.trans.x);
-                if is_master && !sm.trans.no_log() && db.changed() {
+                sm.trans.run(&db);
+                let rules_changed = db.changed() && iprules::IpRules::changed(&db);
+                if ssc.is_master() && !sm.trans.no_log() && db.changed() {
                     let ser = bincode::serialize(&sm.trans.x.qy).unwrap();
-                    save_transaction(&db, ser);
+                    save_transaction(&db, ser, sm.trans.x.qy.now);
                 }
                 sm.trans.updates = db.save();
+                if rules_changed {
+                    ssc.set_ip_rules(&db);
+                }
                 let _x = sm.reply.send(sm.trans);
             }
         });
@@ -132,11 +209,23 @@ fn main()
                 a = listener.accept() =>
                 {
                     let (stream, src) = a.unwrap();
+                    // Close connections from IP addresses that are denied for all paths ( see login.IpRule ).
+                    if !ss.ip_allowed(&src.ip().to_string(), "") {
+                        continue;
+                    }
+                    // Reply 503 ( Service Unavailable ) without reading the request if there are too many connections.
+                    let Ok(permit) = ss.conn_limit.clone().try_acquire_owned() else {
+                        let retry = share::BUSY_RETRY;
+                        let busy = format!("HTTP/1.1 503\r\nRetry-After: {retry}\r\nContent-Length: 0\r\n\r\n");
+                        let _ = stream.try_write(busy.as_bytes());
+                        continue;
+                    };
                     let ssc = ss.clone();
                     tokio::spawn(async move {
                         if let Err(x) = request::process(stream, src.ip().to_string(), ssc).await {
                             println!("End request process error={:?}", x);
                         }
+                        drop(permit);
                     });  
                 }
                 _ = tokio::signal::ctrl_c() =>
@@ -155,8 +244,49 @@ fn main()
     });
 }
 
+/// Storage limits from program arguments.
+fn limits(args: &Args) -> Limits {
+    let mut limits = Limits::default();
+    limits.blk_cap = args.blk_cap;
+    limits.page_sizes = args.page_sizes;
+    limits.max_div = args.max_div;
+    limits.af_lim.map_lim = args.map_lim;
+    limits.af_lim.rbuf_mem = args.rbuf_mem;
+    limits.af_lim.swbuf = args.swbuf;
+    limits.af_lim.uwbuf = args.uwbuf;
+    limits
+}
+
+/// Construct page storage for the named database file, upd is temporary storage for updates during commit.
+fn page_storage(file: &str, upd: Box<dyn BasicStorage>, limits: &Limits) -> Box<BlockPageStg> {
+    let file = MultiFileStorage::new(file);
+    // let file = atom_file::AnyFileStorage::new(file);
+    let stg = AtomicFile::new_with_limits(file, upd, &limits.af_lim);
+    BlockPageStg::new(stg, limits)
+}
+
+/// Number of semaphore permits for a limit, zero means no limit.
+fn permits(limit: usize) -> usize {
+    if limit == 0 { Semaphore::MAX_PERMITS } else { limit }
+}
+
+/// Get API token for replication, from file or RUSTWEB_REP_TOKEN environment variable.
+/// If the file cannot be read, the error is reported and the program exits.
+fn rep_token(file: &str) -> String {
+    if !file.is_empty() {
+        match std::fs::read_to_string(file) {
+            Ok(token) => return token.trim().to_string(),
+            Err(e) => {
+                println!("Replication token file {file} error={e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    std::env::var("RUSTWEB_REP_TOKEN").unwrap_or_default()
+}
+
 /// Append compressed, serialised transaction to log.Transaction table
-fn save_transaction(db: &DB, bytes: Vec<u8>) {
+fn save_transaction(db: &DB, bytes: Vec<u8>, now: i64) {
     if let Some(t) = db.get_table(&ObjRef::new("log", "Transaction")) {
         let bytes = flate3::deflate(&bytes);
         let v = LVec::from(&*bytes);
@@ -164,6 +294,10 @@ fn save_transaction(db: &DB, bytes: Vec<u8>) {
         let mut row = t.row();
         row.id = t.alloc_id(db);
         row.values[0] = bytes;
+        // Time column ( see log.Prune ), may not exist in older databases.
+        if row.values.len() > 1 {
+            row.values[1] = Value::Int(now + share::TICKS_OFFSET);
+        }
         t.insert(db, &mut row);
     }
 }
@@ -185,8 +319,22 @@ async fn term() {
 
 /// Extra SQL builtin functions
 mod builtins;
+/// Cryptographic functions
+mod crypt;
 /// SQL initialisation string
 mod init;
+/// IP address allow and deny rules
+mod iprules;
+/// JSON Web Tokens
+mod jwt;
+/// OpenID Connect login
+mod oidc;
+/// Memory accounting for transactions
+mod meter;
+/// Point-in-time recovery from the transaction log
+mod recover;
+/// Streaming replication protocol
+mod replicate;
 /// http request processing
 mod request;
 /// Shared data structures
@@ -198,11 +346,14 @@ use clap::Parser;
 
 /// Command line arguments.
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
     /// Port to listen on
-    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
-    port: u16,
+    #[arg(required = true, value_parser = clap::value_parser!(u16).range(1..))]
+    port: Option<u16>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 
     /// Ip Address to listen on
     #[arg(long, value_parser, default_value = "0.0.0.0")]
@@ -224,6 +375,66 @@ struct Args {
     #[arg(long, value_parser, default_value_t = 1_000_000)]
     dos_write: u64,
 
+    /// Denial of Service Memory Limit ( peak memory used by requests, in KB )
+    #[arg(long, value_parser, default_value_t = 100_000)]
+    dos_mem: u64,
+
+    /// Denial of Service Count Refill Rate ( per second )
+    #[arg(long, value_parser, default_value_t = 10)]
+    dos_count_rate: u64,
+
+    /// Denial of Service Read Request Refill Rate ( per second )
+    #[arg(long, value_parser, default_value_t = 10_000)]
+    dos_read_rate: u64,
+
+    /// Denial of Service CPU Refill Rate ( per second )
+    #[arg(long, value_parser, default_value_t = 100_000)]
+    dos_cpu_rate: u64,
+
+    /// Denial of Service Write Response Refill Rate ( per second )
+    #[arg(long, value_parser, default_value_t = 10_000)]
+    dos_write_rate: u64,
+
+    /// Denial of Service Memory Refill Rate ( KB per second )
+    #[arg(long, value_parser, default_value_t = 10_000)]
+    dos_mem_rate: u64,
+
+    /// Denial of Service Subnet Limit Multiplier ( limits for each /24 IPv4 or /64 IPv6 subnet, as a multiple of the per address limits, 0 means no subnet limits )
+    #[arg(long, value_parser, default_value_t = 16)]
+    dos_subnet: u64,
+
+    /// Denial of Service Network Limit Multiplier ( limits for each /16 IPv4 or /32 IPv6 network, as a multiple of the per address limits, 0 means no network limits )
+    #[arg(long, value_parser, default_value_t = 0)]
+    dos_network: u64,
+
+    /// Denial of Service Failed Login Limit ( per IP address, one failure is forgotten every 10 seconds )
+    #[arg(long, value_parser, default_value_t = 10)]
+    dos_fail: u64,
+
+    /// Interval for saving Denial of Service information to rustweb.dos ( seconds, 0 means not saved ). Saved information is loaded on startup.
+    #[arg(long, value_parser, default_value_t = 0)]
+    dos_save: u64,
+
+    /// Limit on concurrent connections ( 0 means no limit )
+    #[arg(long, value_parser, default_value_t = 1000)]
+    max_conn: usize,
+
+    /// Limit on concurrent connections from an IP address ( 0 means no limit )
+    #[arg(long, value_parser, default_value_t = 20)]
+    max_conn_ip: usize,
+
+    /// Addresses of proxies trusted to give the client address in the X-Real-IP header ( comma separated )
+    #[arg(long, value_delimiter = ',', default_value = "127.0.0.1,::1")]
+    proxy: Vec<std::net::IpAddr>,
+
+    /// Limit on concurrent read-only requests ( 0 means no limit )
+    #[arg(long, value_parser, default_value_t = 100)]
+    max_readers: usize,
+
+    /// Limit on read-write requests waiting to update the database ( 0 means no limit )
+    #[arg(long, value_parser, default_value_t = 100)]
+    max_writers: usize,
+
     /// Memory limit for page cache (in MB)
     #[arg(long, value_parser, default_value_t = 100)]
     mem: usize,
@@ -236,6 +447,22 @@ struct Args {
     #[arg(long, value_parser, default_value = "")]
     login: String,
 
+    /// File containing API token for replication ( alternatively set RUSTWEB_REP_TOKEN )
+    #[arg(long, value_parser, default_value = "")]
+    rep_token_file: String,
+
+    /// Name of this replication server, the master keeps log records until each named replication server has them
+    #[arg(long, value_parser, default_value = "replica")]
+    rep_name: String,
+
+    /// How this replication server handles requests that update the database: forward them to the master, reject them, or redirect them to the master
+    #[arg(long, value_enum, default_value_t = replicate::Writes::Forward)]
+    rep_writes: replicate::Writes,
+
+    /// Seconds without contact from the master after which this replication server promotes itself to master ( 0 means never, see /log-promote )
+    #[arg(long, value_parser, default_value_t = 0)]
+    promote_after: u64,
+
     /// Trace query time.
     #[arg(long, value_parser, default_value_t = false)]
     tracetime: bool,
@@ -275,4 +502,56 @@ struct Args {
     /// Memory for buffering writes to temporary storage
     #[arg(long, value_parser, default_value_t = 0x100000)]
     uwbuf: usize,
+
+    /// Password hashing memory cost (in KB)
+    #[arg(long, value_parser, default_value_t = 19456)]
+    pw_mem: u32,
+
+    /// Password hashing time cost (iterations)
+    #[arg(long, value_parser, default_value_t = 2)]
+    pw_time: u32,
+
+    /// Password hashing parallelism
+    #[arg(long, value_parser, default_value_t = 1)]
+    pw_par: u32,
+}
+
+/// Commands other than running the server.
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Create a new database from a base database, applying log.Transaction records up to a given Id or time
+    Recover(RecoverArgs),
+}
+
+/// Arguments for point-in-time recovery ( see recover::run ).
+#[derive(clap::Args)]
+struct RecoverArgs {
+    /// Base database file, e.g. a backup copy of rustweb.rustdb ( not the file of a running server )
+    base: String,
+
+    /// Database file with the log.Transaction records to apply, e.g. a copy of the master or a replication server database
+    log: String,
+
+    /// New database file to create
+    out: String,
+
+    /// Id of the first record to apply ( default is log.Status.Done for a replication server database, otherwise the next Id the base database would log )
+    #[arg(long)]
+    from: Option<u64>,
+
+    /// Id of the last record to apply
+    #[arg(long)]
+    to_id: Option<u64>,
+
+    /// Apply records for transactions up to this time ( UTC ), as YYYY-MM-DD HH:MM:SS or seconds since 1970
+    #[arg(long, value_parser = recover::parse_time)]
+    to_time: Option<i64>,
+
+    /// Ids of records not to apply, e.g. an accidental DROP ( comma separated )
+    #[arg(long, value_delimiter = ',')]
+    skip: Vec<u64>,
+
+    /// Server key file, needed by transactions that sign or encrypt cookies
+    #[arg(long, default_value = "rustweb.key")]
+    key: String,
 }
@@ -0,0 +1,153 @@
+use rustdb::alloc::{GVec, LRc, LString};
+use rustdb::{GenTransaction, Transaction, Value};
+use std::alloc::{GlobalAlloc, Layout};
+use std::any::Any;
+use std::cell::Cell;
+use std::sync::Arc;
+
+thread_local! {
+    /// Bytes currently allocated by the thread ( less bytes freed ).
+    static USED: Cell<isize> = const { Cell::new(0) };
+    /// Most bytes allocated by the thread since the last call to start.
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+/// Global allocator that counts memory allocated by each thread, so memory used by a transaction can be measured.
+pub struct Meter;
+
+/// Add n bytes to the count for the current thread.
+fn count(n: isize) {
+    let _ = USED.try_with(|used| {
+        let u = used.get() + n;
+        used.set(u);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(u)));
+    });
+}
+
+unsafe impl GlobalAlloc for Meter {
+    unsafe fn alloc(&self, lay: Layout) -> *mut u8 {
+        count(lay.size() as isize);
+        unsafe { rustdb::alloc::Perm.alloc(lay) }
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, lay: Layout) {
+        count(-(lay.size() as isize));
+        unsafe { rustdb::alloc::Perm.dealloc(p, lay) }
+    }
+
+    unsafe fn realloc(&self, p: *mut u8, lay: Layout, new_size: usize) -> *mut u8 {
+        count(new_size as isize - lay.size() as isize);
+        unsafe { rustdb::alloc::Perm.realloc(p, lay, new_size) }
+    }
+}
+
+/// Start measuring peak memory for the current thread.
+fn start() -> isize {
+    let used = USED.with(|u| u.get());
+    PEAK.with(|p| p.set(used));
+    used
+}
+
+/// Peak memory allocated by the current thread since start.
+fn peak(base: isize) -> u64 {
+    PEAK.with(|p| (p.get() - base).max(0) as u64)
+}
+
+/// Transaction that measures the peak memory it uses ( allocated by the thread running it, including the output ),
+/// and is aborted if this exceeds a limit. The memory is checked each time the transaction calls back ( e.g. to select values,
+/// read arguments or run a builtin function ).
+pub struct Metered {
+    /// The transaction being run.
+    pub x: GenTransaction,
+    /// Limit on memory ( in bytes ).
+    limit: u64,
+    /// Memory allocated when the transaction started.
+    base: isize,
+    /// Peak memory when the limit was exceeded.
+    exceeded: Option<u64>,
+}
+
+impl Metered {
+    /// Start running transaction x with the given memory limit ( in bytes ).
+    pub fn new(x: GenTransaction, limit: u64) -> Self {
+        Self {
+            x,
+            limit,
+            base: start(),
+            exceeded: None,
+        }
+    }
+
+    /// Peak memory used so far ( in bytes ).
+    pub fn peak(&self) -> u64 {
+        self.exceeded.unwrap_or_else(|| peak(self.base))
+    }
+
+    /// Peak memory if the limit was exceeded, in which case the transaction was aborted.
+    pub fn exceeded(&self) -> Option<u64> {
+        self.exceeded
+    }
+
+    /// Abort the transaction if the memory limit has been exceeded. The panic is caught by DB::run.
+    fn check(&mut self) {
+        let used = self.peak();
+        if self.exceeded.is_none() && used > self.limit {
+            self.exceeded = Some(used);
+            panic!("memory limit exceeded ( {}KB )", self.limit >> 10);
+        }
+    }
+}
+
+impl Transaction for Metered {
+    fn status_code(&mut self, code: i64) {
+        self.x.status_code(code);
+        self.check();
+    }
+
+    fn header(&mut self, name: &str, value: &str) {
+        self.x.header(name, value);
+        self.check();
+    }
+
+    fn selected(&mut self, values: &[Value]) {
+        self.x.selected(values);
+        self.check();
+    }
+
+    fn global(&self, kind: i64) -> i64 {
+        self.x.global(kind)
+    }
+
+    fn arg(&mut self, kind: i64, name: &str) -> LRc<LString> {
+        self.check();
+        self.x.arg(kind, name)
+    }
+
+    fn file_attr(&mut self, fnum: i64, atx: i64) -> LRc<LString> {
+        self.check();
+        self.x.file_attr(fnum, atx)
+    }
+
+    fn file_content(&mut self, fnum: i64) -> Arc<GVec<u8>> {
+        self.check();
+        self.x.file_content(fnum)
+    }
+
+    fn set_error(&mut self, err: &str) {
+        self.x.set_error(err)
+    }
+
+    fn get_error(&mut self) -> LRc<LString> {
+        self.x.get_error()
+    }
+
+    fn set_extension(&mut self, ext: Box<dyn Any + Send + Sync>) {
+        // Builtin functions put the extension back when they have finished.
+        self.x.set_extension(ext);
+        self.check();
+    }
+
+    fn get_extension(&mut self) -> Box<dyn Any + Send + Sync> {
+        self.x.get_extension()
+    }
+}
@@ -0,0 +1,206 @@
+use crate::jwt::Jwt;
+use crate::share::SharedState;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// Time that issuer metadata and keys are cached.
+const CACHE_TIME: Duration = Duration::from_secs(3600);
+
+/// Identity provider settings ( from login.OidcProvider ).
+pub struct Provider {
+    /// Issuer URL.
+    pub issuer: String,
+    /// Client id, registered with the provider.
+    pub client_id: String,
+    /// Client secret, blank for a public client.
+    pub secret: String,
+    /// Callback URL, registered with the provider.
+    pub redirect: String,
+}
+
+/// Issuer metadata ( from OpenID Connect Discovery ) and signing keys.
+pub struct Issuer {
+    meta: Value,
+    jwks: Value,
+    fetched: Instant,
+}
+
+impl Issuer {
+    /// Fetch metadata and keys for issuer.
+    fn fetch(issuer: &str) -> Result<Self, String> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let meta = get_json(&url)?;
+        if meta["issuer"] != issuer {
+            return Err(format!("discovery issuer mismatch {}", meta["issuer"]));
+        }
+        let jwks_uri = meta["jwks_uri"].as_str().ok_or("no jwks_uri")?;
+        let jwks = get_json(jwks_uri)?;
+        Ok(Self {
+            meta,
+            jwks,
+            fetched: Instant::now(),
+        })
+    }
+
+    /// Get endpoint URL from metadata.
+    fn endpoint(&self, name: &str) -> Result<String, String> {
+        match self.meta[name].as_str() {
+            Some(url) => Ok(url.to_string()),
+            None => Err(format!("no {name} in discovery metadata")),
+        }
+    }
+
+    /// Find the key for a token. If the token has no kid, the first key of the right type is used.
+    fn key(&self, jwt: &Jwt) -> Option<Value> {
+        let kty = if jwt.alg().starts_with("ES") {
+            "EC"
+        } else {
+            "RSA"
+        };
+        let keys = self.jwks["keys"].as_array()?;
+        keys.iter()
+            .find(|k| {
+                if jwt.kid().is_empty() {
+                    k["kty"] == kty
+                } else {
+                    k["kid"] == jwt.kid()
+                }
+            })
+            .cloned()
+    }
+}
+
+/// Get HTTP client.
+fn client() -> reqwest::blocking::Client {
+    reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+}
+
+/// Check response status and parse JSON body.
+fn to_json(response: reqwest::Result<reqwest::blocking::Response>) -> Result<Value, String> {
+    let response = response.map_err(|e| e.to_string())?;
+    let status = response.status();
+    let body = response.text().map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("status={status} body={body}"));
+    }
+    serde_json::from_str(&body).map_err(|e| e.to_string())
+}
+
+/// Fetch JSON from url.
+fn get_json(url: &str) -> Result<Value, String> {
+    to_json(client().get(url).send())
+}
+
+/// Use issuer metadata, fetching it if it is not cached, has expired, or refresh is true.
+fn with_issuer<T>(
+    ss: &SharedState,
+    issuer: &str,
+    refresh: bool,
+    f: impl FnOnce(&Issuer) -> T,
+) -> Result<T, String> {
+    {
+        let m = ss.oidc.lock().unwrap();
+        if let Some(x) = m.get(issuer)
+            && !refresh
+            && x.fetched.elapsed() < CACHE_TIME
+        {
+            return Ok(f(x));
+        }
+    }
+    let x = Issuer::fetch(issuer)?;
+    let result = f(&x);
+    ss.oidc.lock().unwrap().insert(issuer.to_string(), x);
+    Ok(result)
+}
+
+/// Get the URL of the authorization endpoint, to which the user is redirected to log in.
+/// The PKCE code challenge is derived from verifier ( S256 method ).
+pub fn auth_url(
+    ss: &SharedState,
+    p: &Provider,
+    scopes: &str,
+    state: &str,
+    nonce: &str,
+    verifier: &str,
+) -> Result<String, String> {
+    let endpoint = with_issuer(ss, &p.issuer, false, |x| {
+        x.endpoint("authorization_endpoint")
+    })??;
+    let challenge = crate::crypt::b64(&crate::crypt::sha256(verifier.as_bytes()));
+    let scopes = if scopes.is_empty() { "openid" } else { scopes };
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", &p.client_id),
+        ("redirect_uri", &p.redirect),
+        ("scope", scopes),
+        ("state", state),
+        ("nonce", nonce),
+        ("code_challenge", &challenge),
+        ("code_challenge_method", "S256"),
+    ])
+    .unwrap();
+    let sep = if endpoint.contains('?') { '&' } else { '?' };
+    Ok(format!("{endpoint}{sep}{query}"))
+}
+
+/// Exchange an authorization code for tokens, and validate the ID token.
+/// Result is the ID token claims.
+pub fn claims(
+    ss: &SharedState,
+    p: &Provider,
+    code: &str,
+    verifier: &str,
+    nonce: &str,
+    now_secs: i64,
+) -> Result<Value, String> {
+    let issuer = p.issuer.as_str();
+    let endpoint = with_issuer(ss, issuer, false, |x| x.endpoint("token_endpoint"))??;
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &p.redirect),
+        ("client_id", &p.client_id),
+        ("code_verifier", verifier),
+    ];
+    let mut req = client()
+        .post(&endpoint)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(serde_urlencoded::to_string(form).unwrap());
+    if !p.secret.is_empty() {
+        // client_secret_basic authentication ( RFC 6749 section 2.3.1 ).
+        let id = urlencoding::encode(&p.client_id);
+        req = req.basic_auth(id, Some(urlencoding::encode(&p.secret)));
+    }
+    let tokens = to_json(req.send())?;
+    let id_token = tokens["id_token"].as_str().ok_or("no id_token")?;
+    let jwt = Jwt::decode(id_token).ok_or("invalid id_token")?;
+
+    // Keys may have been rotated, so if the key is not found, fetch them again.
+    let mut key = with_issuer(ss, issuer, false, |x| x.key(&jwt))?;
+    if key.is_none() {
+        key = with_issuer(ss, issuer, true, |x| x.key(&jwt))?;
+    }
+    let key = key.ok_or("signing key not found")?;
+    if !jwt.verify_jwk(&key) {
+        return Err(format!("invalid id_token signature alg={}", jwt.alg()));
+    }
+    if jwt.claims["iss"] != issuer {
+        return Err("id_token issuer mismatch".to_string());
+    }
+    if !jwt.check_aud(&p.client_id) {
+        return Err("id_token audience mismatch".to_string());
+    }
+    if !jwt.check_time(now_secs) {
+        return Err("id_token expired".to_string());
+    }
+    if jwt.claims["nonce"] != nonce {
+        return Err("id_token nonce mismatch".to_string());
+    }
+    Ok(jwt.claims)
+}
@@ -0,0 +1,210 @@
+use crate::share::TransExt;
+use rustdb::{DB, Database, GenQuery, GenTransaction, Limits, MemFile, ObjRef, SharedPagedData};
+use std::sync::Arc;
+
+/// Create a new database file by copying a base database, then applying log.Transaction records from a log database
+/// ( see log.Roll ), up to the Id or time given, skipping any Ids given. Each record is reported with its query ( see DESERIALISE ).
+/// If the base database has log.Status, Done is updated, so the records applied are not applied again by log.Roll.
+/// The database files must not be in use by a running server.
+pub fn run(a: &crate::RecoverArgs, limits: &Limits) -> Result<(), String> {
+    if std::path::Path::new(&a.out).exists() {
+        return Err(format!("{} already exists", a.out));
+    }
+    std::fs::copy(&a.base, &a.out).map_err(|e| format!("copying {} failed {e}", a.base))?;
+    let key = std::fs::read(&a.key).ok().and_then(|b| b.try_into().ok());
+    if key.is_none() {
+        println!("Server key {} not found", a.key);
+    }
+
+    let bmap = Arc::new(crate::builtins::get_bmap());
+    let log = open(&a.log, limits);
+    let ldb = Database::new(log.new_reader(), "", bmap.clone());
+    let out = open(&a.out, limits);
+    let db = Database::new(out.new_writer(), "", bmap);
+
+    let t = ldb
+        .get_table(&ObjRef::new("log", "Transaction"))
+        .ok_or(format!("{} has no log.Transaction table", a.log))?;
+    let end = t.get_id_gen(&ldb) as u64;
+    let from = a.from.unwrap_or_else(|| position(&db));
+    let last = a.to_id.map_or(end, |id| end.min(id + 1));
+    println!(
+        "Applying log.Transaction Id={from}..{} from {} to {}",
+        last.saturating_sub(1),
+        a.log,
+        a.out
+    );
+
+    let (mut applied, mut skipped, mut errors) = (0, 0, 0);
+    let mut next = from;
+    while next < last {
+        let id = next;
+        let Some((pp, off)) = t.id_get(&ldb, id) else {
+            return Err(format!(
+                "log.Transaction Id={id} not found ( see log.Prune )"
+            ));
+        };
+        let ser = {
+            let p = &pp.borrow();
+            flate3::inflate(&t.access(p, off).bin(&ldb, 0))
+        };
+        let qy: GenQuery = bincode::deserialize(&ser).map_err(|e| format!("Id={id} {e}"))?;
+        if a.to_time.is_some_and(|to| qy.now > to) {
+            break;
+        }
+        next += 1;
+        let sql = crate::builtins::deserialise(&ser);
+        if a.skip.contains(&id) {
+            println!("Skipped Id={id} {sql}");
+            skipped += 1;
+            continue;
+        }
+        println!("Applying Id={id} {sql}");
+        let mut tr = GenTransaction::new();
+        tr.qy = qy;
+        let mut ext = TransExt::new();
+        ext.key = key;
+        tr.ext = ext;
+        let sql = tr.qy.sql.clone();
+        db.run(&sql, &mut tr);
+        db.save();
+        applied += 1;
+        if !tr.rp.err.is_empty() {
+            println!("Error Id={id} error={}", tr.rp.err);
+            errors += 1;
+        }
+    }
+    if db.get_table(&ObjRef::new("log", "Status")).is_some() {
+        let mut tr = GenTransaction::new();
+        db.run(
+            &format!("UPDATE log.Status SET Done = {next} WHERE true"),
+            &mut tr,
+        );
+        db.save();
+    }
+    out.wait_complete();
+    println!(
+        "Recovery complete applied={applied} skipped={skipped} errors={errors} next Id={next}"
+    );
+    Ok(())
+}
+
+/// Open a database file. Updates are committed using memory, rather than a temporary file.
+fn open(file: &str, limits: &Limits) -> Arc<SharedPagedData> {
+    let ps = crate::page_storage(file, MemFile::new(), limits);
+    SharedPagedData::new_from_ps(ps)
+}
+
+/// Id of the first log.Transaction record not yet applied to a database:
+/// log.Status.Done for a replication server database, otherwise the next Id the database would log.
+fn position(db: &DB) -> u64 {
+    if let Some(t) = db.get_table(&ObjRef::new("log", "Status"))
+        && let Some((pp, off)) = t.scan(db).next()
+    {
+        let p = &pp.borrow();
+        return t.access(p, off).int(0) as u64;
+    }
+    db.get_table(&ObjRef::new("log", "Transaction"))
+        .map_or(0, |t| t.get_id_gen(db) as u64)
+}
+
+/// Parse a time ( UTC ) given as YYYY-MM-DD, YYYY-MM-DD HH:MM, YYYY-MM-DD HH:MM:SS or seconds since 1970.
+/// Result is microseconds since 1970 ( as for the transaction time ).
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    let bad = || format!("bad time {s}");
+    if let Ok(secs) = s.parse::<i64>() {
+        return Ok(secs * 1_000_000);
+    }
+    let (date, time) = s.trim().split_once([' ', 'T']).unwrap_or((s.trim(), "0:0"));
+    let num = |x: &str| x.parse::<i64>().map_err(|_| bad());
+    let d: Vec<&str> = date.split('-').collect();
+    let t: Vec<&str> = time.split(':').collect();
+    if d.len() != 3 || t.len() < 2 || t.len() > 3 {
+        return Err(bad());
+    }
+    let (y, m, day) = (num(d[0])?, num(d[1])?, num(d[2])?);
+    let (h, min) = (num(t[0])?, num(t[1])?);
+    let sec = if t.len() == 3 { num(t[2])? } else { 0 };
+    let leap = y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+    let month_days = match m {
+        2 => 28 + leap as i64,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&m)
+        || !(1..=month_days).contains(&day)
+        || !(0..=23).contains(&h)
+        || !(0..=59).contains(&min)
+        || !(0..=59).contains(&sec)
+    {
+        return Err(bad());
+    }
+    // Days since 1970-01-01 ( civil calendar ).
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Ok(((days * 24 + h) * 60 + min) * 60_000_000 + sec * 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: &str) -> i64 {
+        parse_time(s).unwrap() / 1_000_000
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(secs("0"), 0);
+        assert_eq!(secs("1709208000"), 1709208000);
+        assert_eq!(secs("-1"), -1);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(secs("1970-01-01"), 0);
+        assert_eq!(secs("2024-03-01"), 1709251200);
+        assert_eq!(secs("2024-02-29 12:00"), 1709208000);
+        assert_eq!(secs("2024-02-29T12:00"), 1709208000);
+        assert_eq!(secs("2100-12-31 23:59:59"), 4133980799);
+    }
+
+    #[test]
+    fn leap_years() {
+        assert_eq!(secs("2000-02-29"), 951782400);
+        assert_eq!(secs("1600-02-29 01:02:03"), -11670994677);
+        assert!(parse_time("2023-02-29").is_err());
+        assert!(parse_time("1900-02-29").is_err());
+        assert!(parse_time("2100-02-29").is_err());
+    }
+
+    #[test]
+    fn before_1970() {
+        assert_eq!(secs("1969-12-31 23:59:59"), -1);
+        assert_eq!(secs("1960-01-01"), -315619200);
+        assert_eq!(secs("1900-03-01"), -2203891200);
+    }
+
+    #[test]
+    fn invalid() {
+        for s in [
+            "",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-04-31",
+            "2024-01-01 24:00",
+            "2024-01-01 -1:00",
+            "2024-01-01 12:60",
+            "2024-01-01 12:00:60",
+            "2024-01-01 12",
+            "2024-01",
+            "yesterday",
+        ] {
+            assert!(parse_time(s).is_err(), "{s}");
+        }
+    }
+}
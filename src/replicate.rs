@@ -0,0 +1,642 @@
+use crate::share::{AuditEvent, SharedState, Trans, TransExt, U_MEM};
+use rustdb::alloc::GString;
+use rustdb::gentrans::GenQuery;
+use rustdb::{Database, ObjRef};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Protocol name used to upgrade a /log-stream request ( see LOGSTREAM ).
+pub const PROTOCOL: &str = "rustweb-replication";
+
+/// Response sent by the master to switch the connection to the replication protocol.
+const SWITCH: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: rustweb-replication\r\n\r\n";
+
+/// Response sent by a server that has been fenced ( see SharedState::fence ), or that is fenced by the request.
+pub const FENCED: &[u8] = b"HTTP/1.1 409 Conflict\r\nContent-Length: 0\r\n\r\n";
+
+/// Response sent by a fenced server to a promoted replication server other than the one that fenced it ( see serve ).
+const SUPERSEDED: &[u8] = b"HTTP/1.1 410 Gone\r\nContent-Length: 0\r\n\r\n";
+
+/// Seconds between batches sent by the master when there are no new transactions.
+pub const HEARTBEAT: u64 = 30;
+
+/// Seconds a replication server waits for a batch before reconnecting.
+pub const TIMEOUT: u64 = 3 * HEARTBEAT;
+
+/// Seconds after which a replication server using a session cookie reconnects ( so the session is renewed ).
+pub const RENEW: u64 = 600;
+
+/// Minimum seconds between saving the position acknowledged by a replication server ( see log.Ack ).
+const ACK_SAVE: u64 = 10;
+
+/// Maximum number of records sent but not yet acknowledged.
+const WINDOW: u64 = 1000;
+
+/// Maximum number of records in a batch.
+const BATCH_RECORDS: u64 = 100;
+
+/// Maximum size of the records in a batch ( bytes ).
+const BATCH_BYTES: usize = 1 << 20;
+
+/// Largest frame a replication server will accept ( bytes ).
+const MAX_FRAME: usize = 64 << 20;
+
+/// Seconds a replication server waits for the master to respond to a forwarded request.
+const FORWARD_TIMEOUT: u64 = 60;
+
+/// How a replication server handles requests that update the database ( see --rep-writes ).
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Writes {
+    /// Forward the request to the master, and return the master's response.
+    Forward,
+    /// Reject the request with 503 ( Service Unavailable ).
+    Reject,
+    /// Redirect the request to the master with 307 ( Temporary Redirect ).
+    Redirect,
+}
+
+/// Response from the master to a forwarded request ( see forward ).
+#[derive(Default, Serialize, Deserialize)]
+struct ForwardReply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    output: Vec<u8>,
+}
+
+/// Audit events recorded by a transaction on a replication server, sent to the master ( see audit_to_master ).
+#[derive(Serialize, Deserialize)]
+struct AuditForward {
+    events: Vec<AuditEvent>,
+    uid: String,
+    ip: String,
+    path: String,
+    err: String,
+    now: i64,
+}
+
+/// Batch of log.Transaction records sent from the master to a replication server.
+/// The records are compressed ( see save_transaction ). A batch with no records is a heartbeat.
+#[derive(Serialize, Deserialize)]
+pub struct Batch {
+    /// Id of the first record.
+    pub first: u64,
+    /// Id of the next record to be generated by the master ( see log.NextTransaction ).
+    pub end: u64,
+    /// Replication term of the master ( see log.Term ).
+    pub term: u64,
+    /// The records.
+    pub records: Vec<Vec<u8>>,
+}
+
+/// Replication status of a replication server.
+#[derive(Default)]
+pub struct ReplicaStatus {
+    /// Whether connected to the master.
+    pub connected: bool,
+    /// Id of the next record needed from the master.
+    pub fetch: u64,
+    /// Id of the next record to be generated by the master, as of the last batch received.
+    pub end: u64,
+    /// When the last batch was received.
+    pub contact: Option<Instant>,
+    /// When the replication server was last up to date with the master.
+    pub current: Option<Instant>,
+    /// Last connection error.
+    pub error: String,
+    /// Highest replication term of the master seen, a master with an earlier term has been superseded.
+    pub term: u64,
+}
+
+impl ReplicaStatus {
+    /// Record a batch received from the master. end is from the batch, fetch is the Id of the next record needed.
+    pub fn received(&mut self, end: u64, fetch: u64) {
+        let now = Instant::now();
+        self.connected = true;
+        self.fetch = fetch;
+        self.end = end;
+        self.contact = Some(now);
+        if fetch >= end {
+            self.current = Some(now);
+        }
+    }
+}
+
+/// Identity of a replication server.
+pub struct ReplicaId {
+    /// IP address of the replication server.
+    pub ip: String,
+    /// Logged in user id of the replication server.
+    pub uid: String,
+    /// Name of the replication server ( see log.Replica ).
+    pub name: String,
+}
+
+/// A replication server connected to the master.
+pub struct ReplicaConn {
+    /// Identity of the replication server.
+    pub id: ReplicaId,
+    /// When the replication server connected.
+    pub start: Instant,
+    /// Id of the next record to be sent.
+    pub sent: u64,
+    /// Id of the next record needed, as last acknowledged.
+    pub acked: u64,
+    /// When the last acknowledgement was received.
+    pub acked_at: Instant,
+    /// Id of the next record to be generated, as of the last batch sent.
+    pub end: u64,
+}
+
+/// Replication status as a JSON object ( see REPINFO ).
+/// For a replication server, the status of the connection to the master: connected, fetch, end, behind ( records ),
+/// lag ( seconds since the replication server was last up to date, zero if connected and up to date ),
+/// contact ( seconds since the last batch ) and error.
+/// term is the replication term, and fenced is whether the server has been superseded by a promoted replication server.
+/// For the master, replicas lists the connected replication servers, each with ip, uid, connected ( seconds ),
+/// name, sent, acked, behind ( records ) and ack ( seconds since the last acknowledgement ).
+pub fn info(ss: &SharedState) -> String {
+    let secs = |t: Option<Instant>| t.map(|t| t.elapsed().as_secs());
+    let mut result = serde_json::json!({
+        "master": ss.is_master(),
+        "term": ss.term(),
+        "fenced": ss.is_fenced(),
+    });
+    if !ss.is_master() && !ss.replicate_source.is_empty() {
+        let st = ss.rep_status.lock().unwrap();
+        result["connected"] = st.connected.into();
+        result["fetch"] = st.fetch.into();
+        result["end"] = st.end.into();
+        result["behind"] = st.end.saturating_sub(st.fetch).into();
+        let lag = match st.connected && st.fetch >= st.end {
+            true => Some(0),
+            false => secs(st.current),
+        };
+        result["lag"] = lag.into();
+        result["contact"] = secs(st.contact).into();
+        result["error"] = st.error.clone().into();
+    }
+    let conns = ss.rep_conns.lock().unwrap();
+    let mut replicas = Vec::new();
+    for c in conns.values() {
+        replicas.push(serde_json::json!({
+            "ip": c.id.ip,
+            "uid": c.id.uid,
+            "name": c.id.name,
+            "connected": c.start.elapsed().as_secs(),
+            "sent": c.sent,
+            "acked": c.acked,
+            "behind": c.end.saturating_sub(c.acked),
+            "ack": c.acked_at.elapsed().as_secs(),
+        }));
+    }
+    replicas.sort_by(|a, b| b["behind"].as_u64().cmp(&a["behind"].as_u64()));
+    result["replicas"] = replicas.into();
+    result.to_string()
+}
+
+/// Registration of a connected replication server, removed when dropped.
+struct Registration {
+    ss: Arc<SharedState>,
+    key: u64,
+}
+
+impl Registration {
+    fn new(ss: Arc<SharedState>, id: ReplicaId, next: u64) -> Self {
+        let mut conns = ss.rep_conns.lock().unwrap();
+        let key = conns.keys().max().map_or(0, |k| k + 1);
+        let now = Instant::now();
+        let conn = ReplicaConn {
+            id,
+            start: now,
+            sent: next,
+            acked: next,
+            acked_at: now,
+            end: next,
+        };
+        conns.insert(key, conn);
+        drop(conns);
+        Self { ss, key }
+    }
+
+    /// Update the connection details.
+    fn update(&self, f: impl FnOnce(&mut ReplicaConn)) {
+        if let Some(c) = self.ss.rep_conns.lock().unwrap().get_mut(&self.key) {
+            f(c);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.ss.rep_conns.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Stream log.Transaction records to a replication server, starting from Id next.
+/// A batch is sent when the connection starts, then as soon as transactions are committed.
+/// The replication server acknowledges each batch by sending the Id of the next record it needs ( 8 bytes, little-endian ),
+/// and no more than WINDOW records are sent beyond the last acknowledgement. If the replication server is named,
+/// the acknowledged position is saved ( see log.Ack ), so records are kept until it has them. Returns when the connection is closed.
+/// fence is the term and id of a replication server that has been promoted ( see fence_term ). If the term is later than the term of this server,
+/// this server is fenced. A fenced server responds with FENCED, and stops streaming. If two replication servers are promoted with the same term,
+/// the first to fence this server wins: the other gets SUPERSEDED, so it can stop acting as master ( see tasks::fence_loop ).
+pub async fn serve<R, W>(
+    r: &mut R,
+    w: &mut W,
+    ss: Arc<SharedState>,
+    id: ReplicaId,
+    mut next: u64,
+    fence: Option<(u64, String)>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if let Some((term, by)) = fence {
+        if term > ss.term() {
+            ss.fence(term, &by).await;
+        } else if ss.is_fenced() && (term < ss.term() || by != *ss.fenced_by.lock().unwrap()) {
+            w.write_all(SUPERSEDED).await?;
+            return Ok(());
+        }
+    }
+    if ss.is_fenced() {
+        w.write_all(FENCED).await?;
+        return Ok(());
+    }
+    let mut wait_rx = ss.wait_tx.subscribe();
+    w.write_all(SWITCH).await?;
+    let name = id.name.clone();
+    let reg = Registration::new(ss.clone(), id, next);
+    let mut saved: Option<(u64, Instant)> = None;
+    let mut acked = next;
+    let (mut ack, mut got) = ([0; 8], 0);
+    let mut sent: Option<tokio::time::Instant> = None;
+    let beat = Duration::from_secs(HEARTBEAT);
+    loop {
+        if ss.is_fenced() {
+            return Ok(());
+        }
+        let max = acked
+            .saturating_add(WINDOW)
+            .saturating_sub(next)
+            .min(BATCH_RECORDS);
+        let heartbeat = sent.is_none_or(|t| t.elapsed() >= beat);
+        if max > 0 || heartbeat {
+            let ssc = ss.clone();
+            let batch = tokio::task::spawn_blocking(move || read_batch(&ssc, next, max))
+                .await
+                .unwrap();
+            if batch.records.is_empty() && max > 0 && next < batch.end {
+                let msg = format!("log.Transaction Id={next} has been deleted ( see log.Prune )");
+                return Err(std::io::Error::other(msg));
+            }
+            if !batch.records.is_empty() || heartbeat {
+                next += batch.records.len() as u64;
+                reg.update(|c| (c.sent, c.end) = (next, batch.end));
+                write_frame(w, &batch).await?;
+                sent = Some(tokio::time::Instant::now());
+                continue;
+            }
+        }
+        tokio::select! {
+            _ = wait_rx.recv() => {}
+            n = r.read(&mut ack[got..]) =>
+            {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                got += n;
+                if got == 8 {
+                    acked = u64::from_le_bytes(ack);
+                    got = 0;
+                    if acked > next {
+                        let msg = format!("acknowledged Id={acked} has not been sent ( next={next} )");
+                        return Err(std::io::Error::other(msg));
+                    }
+                    reg.update(|c| (c.acked, c.acked_at) = (acked, Instant::now()));
+                    let save = saved.is_none_or(|(k, t)| {
+                        k != acked && t.elapsed() >= Duration::from_secs(ACK_SAVE)
+                    });
+                    if !name.is_empty() && save {
+                        save_ack(&ss, &name, acked).await;
+                        saved = Some((acked, Instant::now()));
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(sent.unwrap() + beat) => {}
+        }
+    }
+}
+
+/// Value of the fence parameter sent by a promoted replication server to fence its old master ( see tasks::fence_loop ).
+/// The term and id of the promotion are signed with the server key, so only a server with the key, after promotion, can fence the master.
+/// The name signed cannot be a cookie name ( see SETCOOKIE ), so a signed cookie cannot be used instead.
+pub fn fence_value(ss: &SharedState, term: u64, id: &str) -> String {
+    crate::crypt::sign(&ss.key, "replicate/fence", &format!("{term} {id}"))
+}
+
+/// Term and id from the fence parameter, None if the signature is not valid ( see fence_value ).
+pub fn fence_term(ss: &SharedState, value: &str) -> Option<(u64, String)> {
+    let (term, id) = crate::crypt::unsign(&ss.key, "replicate/fence", value)?.split_once(' ')?;
+    Some((term.parse().ok()?, id.to_string()))
+}
+
+/// Save the position acknowledged by a named replication server ( see log.Ack ).
+async fn save_ack(ss: &SharedState, name: &str, acked: u64) {
+    let mut st = Trans::new();
+    let name = name.replace('\'', "''");
+    st.x.qy.sql = Arc::new(format!("EXEC log.Ack('{name}',{acked})"));
+    st = ss.process(st).await;
+    if !st.x.rp.err.is_empty() {
+        println!("Saving replication position failed error={}", st.x.rp.err);
+    }
+}
+
+/// Read up to max log.Transaction records starting from Id first.
+fn read_batch(ss: &SharedState, first: u64, max: u64) -> Batch {
+    let db = Database::new(ss.spd.new_reader(), "", ss.bmap.clone());
+    let mut batch = Batch {
+        first,
+        end: first,
+        term: ss.term(),
+        records: Vec::new(),
+    };
+    if let Some(t) = db.get_table(&ObjRef::new("log", "Transaction")) {
+        batch.end = t.get_id_gen(&db) as u64;
+        let mut size = 0;
+        let mut id = first;
+        while id < batch.end && (batch.records.len() as u64) < max && size < BATCH_BYTES {
+            let Some((pp, off)) = t.id_get(&db, id) else {
+                break;
+            };
+            let p = &pp.borrow();
+            let data = t.access(p, off).bin(&db, 0).to_vec();
+            size += data.len();
+            batch.records.push(data);
+            id += 1;
+        }
+    }
+    batch
+}
+
+/// Write a batch, preceded by its length ( 4 bytes, little-endian ).
+async fn write_frame<W: AsyncWrite + Unpin>(w: &mut W, batch: &Batch) -> std::io::Result<()> {
+    let data = bincode::serialize(batch).unwrap();
+    w.write_all(&(data.len() as u32).to_le_bytes()).await?;
+    w.write_all(&data).await?;
+    w.flush().await
+}
+
+/// Read a batch written by write_frame.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    r: &mut R,
+) -> Result<Batch, Box<dyn std::error::Error + Send + Sync>> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len).await?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(format!("replication frame too large len={len}").into());
+    }
+    let mut data = vec![0; len];
+    r.read_exact(&mut data).await?;
+    Ok(bincode::deserialize(&data)?)
+}
+
+/// Whether a request that updates the database must be sent to the master, as this is a replication server.
+/// Paths starting with /log- are always handled locally ( e.g. /log-promote ).
+pub fn to_master(ss: &SharedState, path: &str) -> bool {
+    !ss.is_master()
+        && !ss.is_fenced()
+        && !ss.replicate_source.is_empty()
+        && !path.starts_with("/log-")
+}
+
+/// Send a request that updates the database to the master ( see to_master and --rep-writes ).
+pub async fn send_to_master(ss: &SharedState, t: &mut Trans, ip: &str) {
+    match ss.rep_writes {
+        Writes::Forward => forward(ss, t, ip).await,
+        Writes::Reject => {
+            t.x.rp.status_code = 503;
+            let msg = "This server is a replication server, updates must be sent to the master";
+            t.x.rp.output = msg.as_bytes().to_vec();
+        }
+        Writes::Redirect => {
+            let mut url = ss.replicate_source.clone() + &t.x.qy.path;
+            if !t.x.qy.params.is_empty() {
+                url += "?";
+                url += &serde_urlencoded::to_string(&t.x.qy.params).unwrap_or_default();
+            }
+            t.x.rp.status_code = 307; // 307 = HTTP Temporary Redirect
+            let location = (GString::from("Location"), GString::from(url.as_str()));
+            t.x.rp.headers.push(location);
+        }
+    }
+}
+
+/// Forward a request to the master ( see LOGFORWARD ), and set the response to the master's response.
+/// If the master cannot be reached, the response is 503 ( Service Unavailable ).
+async fn forward(ss: &SharedState, t: &mut Trans, ip: &str) {
+    match forward_request(ss, &t.x.qy, ip).await {
+        Ok(reply) => {
+            t.x.rp.status_code = reply.status;
+            for (name, value) in reply.headers {
+                let header = (GString::from(name.as_str()), GString::from(value.as_str()));
+                t.x.rp.headers.push(header);
+            }
+            t.x.rp.output = reply.output;
+        }
+        Err(e) => {
+            println!("Forwarding request to master failed error={e}");
+            t.x.rp.status_code = 503;
+            let msg = "The master server is not available, updates cannot be made";
+            t.x.rp.output = msg.as_bytes().to_vec();
+        }
+    }
+}
+
+/// Post a query to /log-forward on the master, as a multipart file with the requestor IP address.
+/// It is signed with the server key ( see run_forwarded ), so a client that only has replication credentials cannot choose the IP address.
+async fn forward_request(
+    ss: &SharedState,
+    qy: &GenQuery,
+    ip: &str,
+) -> Result<ForwardReply, Box<dyn std::error::Error + Send + Sync>> {
+    let data = bincode::serialize(&(ip, qy))?;
+    let signed = crate::crypt::sign(&ss.key, &signed_name("data"), &crate::crypt::b64(&data));
+    let bytes = post_master(ss, "data", signed.as_bytes()).await?;
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+/// Send SQL deferred by a transaction on a replication server ( see DEFER ) to the master, for example to insert a new session.
+pub async fn defer_to_master(ss: &SharedState, sql: Vec<String>) {
+    send_signed(ss, "defer", &sql).await;
+}
+
+/// Send audit events recorded by a transaction on a replication server ( see AUDIT ) to the master, so they are in its audit log.
+pub async fn audit_to_master(
+    ss: &SharedState,
+    events: Vec<AuditEvent>,
+    ext: &TransExt,
+    path: &str,
+    err: &str,
+    now: i64,
+) {
+    let audit = AuditForward {
+        events,
+        uid: ext.uid.clone(),
+        ip: ext.ip.clone(),
+        path: path.to_string(),
+        err: err.to_string(),
+        now,
+    };
+    send_signed(ss, "audit", &audit).await;
+}
+
+/// Send data to the master ( see run_signed ), signed with the server key, so a client that only has replication credentials cannot send it.
+async fn send_signed<T: Serialize>(ss: &SharedState, part: &str, value: &T) {
+    let result = match bincode::serialize(value) {
+        Ok(data) => {
+            let signed = crate::crypt::sign(&ss.key, &signed_name(part), &crate::crypt::b64(&data));
+            post_master(ss, part, signed.as_bytes()).await
+        }
+        Err(e) => Err(e.into()),
+    };
+    match result {
+        Ok(reply) if &reply[..] == b"ok" => {}
+        Ok(reply) => println!(
+            "Master did not accept {part} error={}",
+            String::from_utf8_lossy(&reply)
+        ),
+        Err(e) => println!("Sending {part} to master failed error={e}"),
+    }
+}
+
+/// Post data to /log-forward on the master, as a multipart file with the given part name, result is the response body.
+async fn post_master(
+    ss: &SharedState,
+    name: &str,
+    data: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    use reqwest::header::{CONTENT_TYPE, ORIGIN};
+    let mut b = [0; 16];
+    crate::crypt::fill_random(&mut b);
+    let boundary = crate::crypt::hex(&b);
+    let mut body = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"forward\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    let req = ss
+        .rep_client
+        .post(ss.replicate_source.clone() + "/log-forward")
+        .header(
+            CONTENT_TYPE,
+            format!("multipart/form-data; boundary={boundary}"),
+        )
+        .header(ORIGIN, &ss.replicate_source)
+        .timeout(Duration::from_secs(FORWARD_TIMEOUT))
+        .body(body);
+    let response = crate::tasks::with_credentials(req, ss).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("bad response status = {status}").into());
+    }
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Name used to sign data sent to the master. It cannot be a cookie name ( see SETCOOKIE ), so a signed cookie cannot be used instead.
+fn signed_name(part: &str) -> String {
+    format!("replicate/{part}")
+}
+
+/// Run data sent by a replication server ( see send_signed ): deferred SQL ( part defer, see defer_to_master ),
+/// or audit events ( part audit, see audit_to_master ). Result is "ok", or an error message.
+pub async fn run_signed(ss: &Arc<SharedState>, part: &str, data: &[u8]) -> Vec<u8> {
+    let value = std::str::from_utf8(data)
+        .ok()
+        .and_then(|s| crate::crypt::unsign(&ss.key, &signed_name(part), s))
+        .and_then(crate::crypt::unb64);
+    let Some(value) = value else {
+        return b"bad signature, the server keys differ".to_vec();
+    };
+    if !ss.is_master() {
+        return b"This server is not the master, updates cannot be made".to_vec();
+    }
+    let bad = |e: bincode::Error| format!("bad {part} error={e}").into_bytes();
+    match part {
+        "defer" => match bincode::deserialize::<Vec<String>>(&value) {
+            Ok(list) => {
+                for sql in list {
+                    ss.deferred(sql).await;
+                }
+            }
+            Err(e) => return bad(e),
+        },
+        _ => match bincode::deserialize::<AuditForward>(&value) {
+            Ok(a) => {
+                let mut ext = TransExt::new();
+                ext.uid = a.uid;
+                ext.ip = a.ip;
+                ss.audit(a.events, &ext, &a.path, &a.err, a.now).await;
+            }
+            Err(e) => return bad(e),
+        },
+    }
+    b"ok".to_vec()
+}
+
+/// Run a request forwarded by a replication server ( see forward ), result is the serialised response.
+/// Login information is set as for any request ( see web.SetUser ), but the SQL run is always web.Main, not any SQL sent.
+/// The requestor IP address is only trusted if the signature is valid, that is the request was forwarded by a server with the server key.
+/// The memory limit is set from the login information, as for any request.
+pub async fn run_forwarded(ss: &Arc<SharedState>, data: &[u8]) -> Vec<u8> {
+    let mut reply = ForwardReply::default();
+    let data = std::str::from_utf8(data)
+        .ok()
+        .and_then(|s| crate::crypt::unsign(&ss.key, &signed_name("data"), s))
+        .and_then(crate::crypt::unb64);
+    let Some(data) = data else {
+        reply.status = 403;
+        reply.output = b"bad signature, the server keys differ".to_vec();
+        return bincode::serialize(&reply).unwrap();
+    };
+    match bincode::deserialize::<(String, GenQuery)>(&data) {
+        Ok((ip, query)) => {
+            let mut t = Trans::new_with_state(ss.clone(), ip);
+            let (sql, now) = (t.x.qy.sql.clone(), t.x.qy.now);
+            t.x.qy = query;
+            t.x.qy.now = now;
+            t.readonly = true;
+            t.x.qy.sql = Arc::new("EXEC web.SetUser()".to_string());
+            t = ss.process_request(t).await;
+            t.x.qy.sql = sql;
+            t.readonly = false;
+            t.set_mem_limit(ss.u_budget(t.uid.clone())[U_MEM] << 10);
+            if !ss.is_master() {
+                t.x.rp.status_code = 503;
+                let msg = "This server is not the master, updates cannot be made";
+                t.x.rp.output = msg.as_bytes().to_vec();
+            } else if t.x.rp.status_code == 200 {
+                t = ss.process_request(t).await;
+                ss.wait(&mut t).await;
+            }
+            reply.status = t.x.rp.status_code;
+            for (name, value) in &t.x.rp.headers {
+                reply.headers.push((name.to_string(), value.to_string()));
+            }
+            reply.output = std::mem::take(&mut t.x.rp.output);
+        }
+        Err(e) => {
+            reply.status = 400;
+            reply.output = format!("bad forwarded request error={e}").into_bytes();
+        }
+    }
+    bincode::serialize(&reply).unwrap()
+}
@@ -1,4 +1,6 @@
-use crate::share::{Error, SharedState, Trans, U_COUNT, U_CPU, U_READ, U_WRITE, UseInfo};
+use crate::share::{
+    Error, SharedState, Trans, U_COUNT, U_CPU, U_MEM, U_READ, U_WRITE, UA, UseInfo,
+};
 use rustdb::alloc::{GBTreeMap, GString, GTemp, GVec, Perm};
 use rustdb::gentrans::GenQuery;
 use std::{str, sync::Arc};
@@ -25,7 +27,11 @@ pub async fn process(
         }
     };
 
-    let (hdrs, outp) = {
+    // Count the connection until the response is written.
+    let ip = r.uid.clone();
+    let conn = ss.ip_connect(&ip);
+
+    let (hdrs, outp, stream) = {
         let mut t = Trans::new_with_state(ss.clone(), r.uid.clone());
         let readonly =
             h.method == b"GET" && !h.args.contains_key("save") || h.args.contains_key("readonly");
@@ -33,33 +39,156 @@ pub async fn process(
         t.x.qy.path = h.path;
         t.x.qy.params = h.args;
         t.x.qy.cookies = h.cookies;
-        let (ct, clen) = (&h.content_type, h.content_length);
-
-        // Set limits based on login info etc.
-        t.readonly = true;
-        let save = t.x.qy.sql.clone();
-        t.x.qy.sql = Arc::new("EXEC web.SetUser()".to_string());
-        t = ss.process(t).await;
-        t.x.qy.sql = save;
-        r.u.limit = ss.u_budget(t.uid.clone());
-        t.readonly = false;
-
-        if ct.is_empty() {
-            // No body.
-        } else if ct == b"application/x-www-form-urlencoded" {
-            let clen: usize = clen.parse()?;
-            let bytes = r.read(clen).await?;
-            t.x.qy.form = serde_urlencoded::from_bytes(&bytes)?;
-        } else if is_multipart(ct) {
-            get_multipart(&mut r, &mut t.x.qy).await?;
+        if let Some(token) = h.bearer {
+            t.x.qy.cookies.insert(GString::from(BEARER), token);
+        }
+
+        // Reject requests from IP addresses that are not allowed for the path ( see login.IpRule ),
+        // or that have too many connections.
+        if !ss.ip_allowed(&r.uid, &t.x.qy.path) {
+            t.x.rp.status_code = 403;
+        } else if conn.is_none() {
+            t.busy();
         } else {
-            t.x.rp.status_code = 501;
+            // Set limits based on login info etc.
+            t.readonly = true;
+            let save = t.x.qy.sql.clone();
+            t.x.qy.sql = Arc::new("EXEC web.SetUser()".to_string());
+            t = ss.process_request(t).await;
+            t.x.qy.sql = save;
+            r.u.limit = ss.u_budget(t.uid.clone());
+            t.readonly = false;
+        }
+
+        // Charge the cost of the request ( see RATECOST ), or reject it if there are not enough tokens.
+        let cost = t.cost();
+        if t.x.rp.status_code == 200 {
+            if r.u.limit[U_COUNT] < cost {
+                t.x.rp.status_code = 429;
+            } else {
+                r.u.used[U_COUNT] = cost;
+            }
+        }
+        if t.x.rp.status_code == 429 {
+            for (name, value) in ss.u_limit_headers(&t.uid, cost) {
+                let value = GString::from(value.as_str());
+                t.x.rp.headers.push((GString::from(name), value));
+            }
+        }
+
+        // Answer Expect: 100-continue, or reject the request so the body is never sent.
+        let mut skip_body = false;
+        if let Some(expect) = &h.expect
+            && !h.content_type.is_empty()
+        {
+            let code = if t.x.rp.status_code != 200 {
+                t.x.rp.status_code // e.g. 429 or 401 set by web.SetUser
+            } else if !expect.eq_ignore_ascii_case("100-continue") {
+                417 // 417 = HTTP Expectation Failed
+            } else {
+                upload_status(&r.u.limit, &h.content_length)
+            };
+            if code == 100 {
+                let budget = r.u.limit[U_WRITE];
+                let cont = b"HTTP/1.1 100 Continue\r\n\r\n";
+                write(&mut w, cont, budget, &mut r.u.used[U_WRITE]).await?;
+            } else {
+                t.x.rp.status_code = code;
+                skip_body = true;
+            }
+        }
+
+        if !skip_body {
+            let body = get_body(&mut r, &h.content_type, &h.content_length, &mut t.x.qy).await;
+            if let Err(e) = body {
+                if e.code == 0 {
+                    return Ok(());
+                }
+                t.x.rp.status_code = e.code;
+            }
         }
         r.read_complete();
 
+        // Reject state-changing requests that may have been forged by another site.
+        if t.x.rp.status_code == 200
+            && (h.method != b"GET" || !readonly)
+            && !csrf_ok(
+                &t.x.qy,
+                h.csrf_token.as_ref(),
+                h.origin.or(h.referer),
+                &h.host,
+                &ss.key,
+            )
+        {
+            t.x.rp.status_code = 403;
+        }
+
+        // Reject login attempts from an IP address with too many recent failed logins.
+        if t.x.rp.status_code == 200
+            && (t.x.qy.form.contains_key("password") || t.x.qy.form.contains_key("totp"))
+        {
+            let fails = ss.u_fails(&r.uid);
+            if fails >= ss.fail_limit {
+                t.x.rp.status_code = 429;
+                let retry = (fails + 1 - ss.fail_limit) * 10;
+                let retry = GString::from(retry.to_string().as_str());
+                t.x.rp.headers.push((GString::from("Retry-After"), retry));
+            }
+        }
+
+        // Reject updates if the server has been superseded by a promoted replication server ( see SharedState::fence ).
+        // An administrator can still unfence the server.
+        if t.x.rp.status_code == 200
+            && !readonly
+            && ss.is_fenced()
+            && t.x.qy.path.as_str() != "/log-unfence"
+        {
+            t.x.rp.status_code = 503;
+            let msg = "This server has been replaced by a promoted replication server, and no longer accepts updates";
+            t.x.rp.output = msg.as_bytes().to_vec();
+        }
+
         if t.x.rp.status_code == 200 {
             t.readonly = readonly;
-            t = ss.process(t).await;
+            t.set_mem_limit(r.u.limit[U_MEM] << 10);
+            if !readonly && crate::replicate::to_master(&ss, &t.x.qy.path) {
+                crate::replicate::send_to_master(&ss, &mut t, &ip).await;
+            } else {
+                t = ss.process_request(t).await;
+                // Delay or wait ( see LOGINFAIL, TRANSWAIT ) while the connection counts against the per-IP limit.
+                ss.wait(&mut t).await;
+            }
+
+            // Run a request, deferred SQL or audit events, forwarded by a replication server ( see LOGFORWARD ).
+            if t.log_forward() && t.x.rp.status_code == 200 {
+                let parts = &t.x.qy.parts;
+                let part = |name: &str| parts.iter().find(|p| p.name.as_str() == name);
+                t.x.rp.output = if let Some(p) = part("defer").or(part("audit")) {
+                    let name = p.name.to_string();
+                    crate::replicate::run_signed(&ss, &name, &p.data.clone()).await
+                } else {
+                    let data = part("data").map(|p| p.data.clone()).unwrap_or_default();
+                    crate::replicate::run_forwarded(&ss, &data).await
+                };
+            }
+
+            // Replication requires the connection to be upgraded ( see LOGSTREAM ).
+            if t.log_stream().is_some()
+                && h.upgrade.as_deref() != Some(crate::replicate::PROTOCOL)
+            {
+                t.x.rp.status_code = 426; // 426 = HTTP Upgrade Required
+            }
+
+            // Promote to master in the background ( see PROMOTE ).
+            if t.promote() {
+                let (ssc, uid) = (ss.clone(), t.uid.clone());
+                tokio::spawn(crate::tasks::promote(ssc, uid, ip.clone()));
+            }
+
+            // Accept updates again ( see UNFENCE ).
+            if t.unfence() {
+                ss.unfence().await;
+            }
 
             if t.is_convert_to_pdf()
             {
@@ -67,7 +196,9 @@ pub async fn process(
             }
             
             r.uid = t.uid.clone();
+            r.u.used[U_COUNT] = t.cost();
             r.u.used[U_CPU] = t.run_time.as_micros() as u64;
+            r.u.used[U_MEM] = t.mem_used >> 10;
             if ss.tracetime {
                 println!(
                     "run time={}µs updates={} readonly={} path={} args={:?}",
@@ -95,15 +226,67 @@ pub async fn process(
                 println!("GTemp::info = {:?}", GTemp::info());
             }
         }
-        (header(&t), t.x.rp.output)
+        let stream = match t.x.rp.status_code {
+            200 => t.log_stream().map(|from| {
+                let params = &t.x.qy.params;
+                let name = params.get("name").map(|s| s.to_string());
+                let fence = params.get("fence");
+                let term = fence.and_then(|s| crate::replicate::fence_term(&ss, s));
+                (from, name.unwrap_or_default(), term)
+            }),
+            _ => None,
+        };
+        (header(&t), t.x.rp.output, stream)
     };
 
+    if let Some((from, name, term)) = stream {
+        let id = crate::replicate::ReplicaId {
+            ip,
+            uid: r.uid.clone(),
+            name,
+        };
+        crate::replicate::serve(&mut r.stream, &mut w, ss, id, from, term).await?;
+        return Ok(());
+    }
+
     let budget = r.u.limit[U_WRITE];
     write(&mut w, &hdrs, budget, &mut r.u.used[U_WRITE]).await?;
     write(&mut w, &outp, budget, &mut r.u.used[U_WRITE]).await?;
     Ok(())
 }
 
+/// Check request has a valid CSRF token.
+/// Requests without a session cookie are not checked, as they carry no credentials.
+/// The token may be sent as the csrf form field ( see CSRFFIELD ) or the X-CSRF-Token header.
+/// If there is no token, the Origin ( or failing that Referer ) header must match the Host header.
+fn csrf_ok(
+    q: &GenQuery,
+    token: Option<&GString>,
+    source: Option<GString>,
+    host: &str,
+    key: &crate::crypt::Key,
+) -> bool {
+    let Some(session) = q.cookies.get(crate::crypt::SESSION_COOKIE) else {
+        return true;
+    };
+    if session.is_empty() {
+        return true;
+    }
+    if let Some(token) = token.or(q.form.get("csrf")) {
+        return crate::crypt::csrf_verify(key, session, token);
+    }
+    source.is_some_and(|s| same_host(&s, host))
+}
+
+/// Check the host of a URL ( from an Origin or Referer header ) is the given host.
+fn same_host(url: &str, host: &str) -> bool {
+    let Some((_scheme, rest)) = url.split_once("://") else {
+        return false;
+    };
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    !host.is_empty() && rest[..end].eq_ignore_ascii_case(host)
+}
+
 /// Get response header.
 fn header(t: &Trans) -> GVec<u8> {
     let mut h = GVec::with_capacity(4096);
@@ -133,6 +316,12 @@ struct Headers {
 
     content_type: GVec<u8>,
     content_length: GString,
+    expect: Option<GString>,
+    origin: Option<GString>,
+    referer: Option<GString>,
+    csrf_token: Option<GString>,
+    bearer: Option<GString>,
+    upgrade: Option<GString>,
 }
 
 impl Headers {
@@ -160,9 +349,17 @@ impl Headers {
                 let b0 = lower(line[0]);
                 let b2 = lower(line[2]);
                 match (b0, b2) {
+                    (b'a', b't') => {
+                        if let Some(line) = line_is(line, b"authorization") {
+                            let line = line.trim_ascii();
+                            if line.len() > 7 && line[..7].eq_ignore_ascii_case(b"bearer ") {
+                                r.bearer = Some(togs(line[7..].trim_ascii())?);
+                            }
+                        }
+                    }
                     (b'c', b'o') => {
                         if let Some(line) = line_is(line, b"cookie") {
-                            r.cookies = cookie_map(line)?;
+                            r.cookies = cookie_map(line);
                         }
                     }
                     (b'c', b'n') => {
@@ -172,13 +369,40 @@ impl Headers {
                             r.content_length = togs(line)?;
                         }
                     }
+                    (b'e', b'p') => {
+                        if let Some(line) = line_is(line, b"expect") {
+                            r.expect = Some(togs(line)?);
+                        }
+                    }
                     (b'h', b's') => {
                         if let Some(line) = line_is(line, b"host") {
                             r.host = togs(line)?;
                         }
                     }
+                    (b'o', b'i') => {
+                        if let Some(line) = line_is(line, b"origin") {
+                            r.origin = Some(togs(line)?);
+                        }
+                    }
+                    (b'r', b'f') => {
+                        if let Some(line) = line_is(line, b"referer") {
+                            r.referer = Some(togs(line)?);
+                        }
+                    }
+                    (b'u', b'g') => {
+                        if let Some(line) = line_is(line, b"upgrade") {
+                            r.upgrade = Some(togs(line)?);
+                        }
+                    }
+                    (b'x', b'c') => {
+                        if let Some(line) = line_is(line, b"x-csrf-token") {
+                            r.csrf_token = Some(togs(line)?);
+                        }
+                    }
                     (b'x', b'r') => {
-                        if let Some(line) = line_is(line, b"x-real-ip") {
+                        if let Some(line) = line_is(line, b"x-real-ip")
+                            && br.proxy
+                        {
                             let ip = tos(line)?;
                             br.u.limit = br.ss.u_budget(ip.clone());
                             br.uid = ip;
@@ -273,71 +497,199 @@ fn bad() -> Error {
     Error { code: 400 }
 }
 
-/// Parse cookie header to a map of cookies.
-fn cookie_map(s: &[u8]) -> Result<GBTreeMap<GString, GString>, Error> {
-    let mut map = GBTreeMap::new();
-    let n = s.len();
-    let mut i = 0;
+/// Name under which a bearer token ( from the Authorization header ) is passed to SQL as a cookie ( see web.Bearer ).
+/// It is passed as a cookie so it is included if the transaction is replicated.
+const BEARER: &str = "$bearer";
 
-    while i < n {
-        while i < n && s[i] == b' ' {
-            i += 1;
-        }
-        let start = i;
-        while i < n && s[i] != b'=' {
-            i += 1;
-        }
-        let name = togs(&s[start..i])?;
-        if i < n {
-            i += 1;
+/// Parse cookie header to a map of cookies ( RFC 6265 ).
+/// Values may be quoted, and are percent-decoded. Malformed pairs are ignored.
+/// Names starting with $ ( RFC 2109 attributes such as $Version ) are ignored.
+/// If a name occurs more than once, the first value is used.
+fn cookie_map(s: &[u8]) -> GBTreeMap<GString, GString> {
+    let mut map = GBTreeMap::new();
+    for pair in s.split(|b| *b == b';') {
+        let Some(eq) = pair.iter().position(|b| *b == b'=') else {
+            continue;
+        };
+        let name = pair[..eq].trim_ascii();
+        let mut value = pair[eq + 1..].trim_ascii();
+        if let Some(v) = value
+            .strip_prefix(b"\"")
+            .and_then(|v| v.strip_suffix(b"\""))
+        {
+            value = v;
         }
-        let start = i;
-        while i < n && s[i] != b';' {
-            i += 1;
+        let value = urlencoding::decode_binary(value);
+        if let (Ok(name), Ok(value)) = (str::from_utf8(name), str::from_utf8(&value))
+            && !name.is_empty()
+            && !name.starts_with('$')
+            && !map.contains_key(name)
+        {
+            map.insert(GString::from(name), GString::from(value));
         }
-        let value = togs(&s[start..i])?;
-        i += 1;
-        map.insert(name, value);
     }
-    Ok(map)
+    map
+}
+
+/// Status for Expect: 100-continue, based on the read budget and content length.
+fn upload_status(limit: &UA, clen: &str) -> u16 {
+    let Ok(clen) = clen.parse::<u64>() else {
+        return 411; // 411 = HTTP Length Required
+    };
+    if limit[U_COUNT] == 0 {
+        429 // 429 = HTTP Too Many Requests
+    } else if clen >> 10 >= limit[U_READ] {
+        413 // 413 = HTTP Content Too Large
+    } else {
+        100
+    }
+}
+
+/// Read the request body, decoding form fields and uploaded files into the query.
+async fn get_body<'a>(
+    br: &mut Buffer<'a>,
+    ct: &[u8],
+    clen: &str,
+    q: &mut GenQuery,
+) -> Result<(), Error> {
+    if ct.is_empty() {
+        return Ok(()); // No body.
+    }
+    let ct = HeaderValue::parse(ct)?;
+    let form = ct.value == "application/x-www-form-urlencoded";
+    if !form && ct.value != "multipart/form-data" {
+        return Err(Error { code: 501 });
+    }
+    if clen.is_empty() {
+        return Err(Error { code: 411 }); // 411 = HTTP Length Required
+    }
+    let clen: usize = clen.parse().map_err(|_| bad())?;
+    let body = br.read(clen).await?;
+    if form {
+        q.form = serde_urlencoded::from_bytes(&body)?;
+    } else {
+        let boundary = ct.param("boundary").ok_or_else(bad)?;
+        get_multipart(&body, boundary.as_bytes(), None, q)?;
+    }
+    Ok(())
 }
 
-/// Check content-type is multipart.
-fn is_multipart(s: &[u8]) -> bool {
-    let temp = b"multipart/form-data";
-    let n = temp.len();
-    s.len() >= n && temp == &s[0..n]
+/// Header value ( such as content-type or content-disposition ) with parameters.
+struct HeaderValue {
+    /// Value before the first ';', in lower case.
+    value: String,
+    /// Parameters, names in lower case.
+    params: Vec<(String, String)>,
 }
 
-/// Extract name and file_name from content-disposition header.
-fn split_cd(s: &[u8]) -> Option<(GString, GString)> {
-    /* Expected input:
-       form-data; name="file"; filename="logo.png"
-    */
-    if let Ok(s) = str::from_utf8(s) {
-        let s = "multipart/".to_string() + s;
-        let (mut name, mut filename) = ("", "");
-        let m: mime::Mime = s.parse().ok()?;
-        if m.subtype() != mime::FORM_DATA {
-            return None;
+impl HeaderValue {
+    /// Parse header value. RFC 2231 extended parameters ( e.g. filename*=UTF-8''x ) take priority.
+    fn parse(s: &[u8]) -> Result<Self, Error> {
+        let s = str::from_utf8(s)?;
+        let mut list = split_params(s).into_iter();
+        let value = list.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let mut result = Self {
+            value,
+            params: Vec::new(),
+        };
+        let mut extended = Vec::new();
+        for p in list {
+            let Some((name, v)) = p.split_once('=') else {
+                continue;
+            };
+            let name = name.trim().to_ascii_lowercase();
+            let v = v.trim();
+            if let Some(name) = name.strip_suffix('*') {
+                extended.push((name.to_string(), decode_ext(v)?));
+            } else {
+                result.params.push((name, unquote(v)));
+            }
         }
-        if let Some(n) = m.get_param("name") {
-            name = n.as_str()
+        for (name, v) in extended {
+            result.params.retain(|(n, _)| *n != name);
+            result.params.push((name, v));
         }
-        if let Some(n) = m.get_param("filename") {
-            filename = n.as_str()
+        Ok(result)
+    }
+
+    /// Get named parameter.
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Split header value on ';', ignoring any ';' inside quoted strings.
+fn split_params(s: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let (mut start, mut quoted, mut escape) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        if escape {
+            escape = false;
+        } else if quoted && c == '\\' {
+            escape = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == ';' && !quoted {
+            result.push(&s[start..i]);
+            start = i + 1;
         }
-        Some((GString::from(name), GString::from(filename)))
-    } else {
-        None
     }
+    result.push(&s[start..]);
+    result
+}
+
+/// Remove quotes and backslash escapes from quoted-string.
+fn unquote(s: &str) -> String {
+    let Some(s) = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) else {
+        return s.to_string();
+    };
+    let mut result = String::with_capacity(s.len());
+    let mut escape = false;
+    for c in s.chars() {
+        if c == '\\' && !escape {
+            escape = true;
+        } else {
+            result.push(c);
+            escape = false;
+        }
+    }
+    result
+}
+
+/// Decode RFC 2231 extended parameter value charset'language'percent-encoded-value.
+fn decode_ext(s: &str) -> Result<String, Error> {
+    let mut it = s.splitn(3, '\'');
+    let (Some(charset), Some(_language), Some(v)) = (it.next(), it.next(), it.next()) else {
+        return Err(bad());
+    };
+    let bytes = urlencoding::decode_binary(v.as_bytes());
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" => Ok(str::from_utf8(&bytes)?.to_string()),
+        "iso-8859-1" => Ok(bytes.iter().map(|b| *b as char).collect()),
+        _ => Err(bad()),
+    }
+}
+
+/// Find needle in hay, starting at from.
+fn find(hay: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > hay.len() {
+        return None;
+    }
+    hay[from..]
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| i + from)
 }
 
 /*
-Parts are delimited by boundary lines.
+Parts are delimited by boundary lines, the boundary is given by the content-type header.
 Each boundary line starts with --
 The final boundary line has an extra --
 Each part has headers, typically Content-Disposition and Content-Type.
+A part may itself be multipart/mixed ( several files for one field ).
 Example:
 ------WebKitFormBoundaryAhgB6VordnzCD84Z
 Content-Disposition: form-data; name="file"; filename=""
@@ -353,59 +705,96 @@ Upload
 
 use rustdb::Part;
 
-/// Parse multipart body.
-async fn get_multipart<'a>(br: &mut Buffer<'a>, q: &mut GenQuery) -> Result<(), Error> {
-    let mut boundary = GVec::new();
-    let n = br.read_until(10, &mut boundary).await?;
-    if n < 4 {
-        return Err(eof())?;
+/// Parse multipart body. field is the field name when parsing a nested multipart/mixed part.
+fn get_multipart(
+    body: &[u8],
+    boundary: &[u8],
+    field: Option<&GString>,
+    q: &mut GenQuery,
+) -> Result<(), Error> {
+    if boundary.is_empty() || boundary.len() > 70 {
+        return Err(bad());
     }
+    let mut delim = b"\r\n--".to_vec();
+    delim.extend_from_slice(boundary);
 
-    let bn = boundary.len() - 2;
-    boundary.truncate(bn);
+    // The first boundary line may not be preceded by CRLF.
+    let mut i = if body.starts_with(&delim[2..]) {
+        delim.len() - 2
+    } else {
+        find(body, &delim, 0).ok_or_else(bad)? + delim.len()
+    };
+    loop {
+        if body[i..].starts_with(b"--") {
+            return Ok(());
+        }
+        // Skip transport padding.
+        while i < body.len() && (body[i] == b' ' || body[i] == b'\t') {
+            i += 1;
+        }
+        if !body[i..].starts_with(b"\r\n") {
+            return Err(bad());
+        }
+        let start = i + 2;
+        let end = find(body, &delim, start).ok_or_else(bad)?;
+        get_part(&body[start..end], field, q)?;
+        i = end + delim.len();
+    }
+}
 
-    let mut got_last = false;
-    while !got_last {
-        let mut part = Part::default();
-        // Read headers
-        let mut line0 = GVec::new();
-        loop {
-            let n = br.read_until(10, &mut line0).await?;
-            if n <= 2 {
-                break;
-            }
-            let line = &line0[0..n - 2];
-            if let Some(line) = line_is(line, b"content-type") {
-                part.content_type = togs(line)?;
-                // Note: if part content-type is multipart, maybe it should be parsed.
-            } else if let Some(line) = line_is(line, b"content-disposition")
-                && let Some((name, file_name)) = split_cd(line)
-            {
-                part.name = name;
-                part.file_name = file_name;
+/// Parse a single part of a multipart body.
+fn get_part(part: &[u8], field: Option<&GString>, q: &mut GenQuery) -> Result<(), Error> {
+    let (headers, data) = if let Some(data) = part.strip_prefix(b"\r\n") {
+        (&part[0..0], data)
+    } else {
+        let n = find(part, b"\r\n\r\n", 0).ok_or_else(bad)?;
+        (&part[0..n], &part[n + 4..])
+    };
+    let mut p = Part::default();
+    let mut ct = None;
+    let mut is_file = field.is_some();
+    for line in headers.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if let Some(line) = line_is(line, b"content-type") {
+            let v = HeaderValue::parse(line)?;
+            p.content_type = togs(line)?;
+            ct = Some(v);
+        } else if let Some(line) = line_is(line, b"content-disposition") {
+            let cd = HeaderValue::parse(line)?;
+            if let Some(name) = cd.param("name") {
+                p.name = GString::from(name);
             }
-            line0.clear();
-        }
-        // Read lines into data looking for boundary.
-        let mut data = GVec::new();
-        loop {
-            let n = br.read_until(10, &mut data).await?;
-            if n == bn + 2 || n == bn + 4 {
-                let start = data.len() - n;
-                if data[start..start + bn] == *boundary {
-                    got_last = n == bn + 4;
-                    data.truncate(start - 2);
-                    break;
-                }
+            if let Some(file_name) = cd.param("filename") {
+                // Only the final path component is meaningful.
+                let file_name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+                p.file_name = GString::from(file_name);
+                is_file = true;
             }
         }
-        if part.content_type.is_empty() {
-            let value = togs(&data)?;
-            q.form.insert(part.name, value);
-        } else {
-            part.data = Arc::new(data);
-            q.parts.push(part);
+    }
+    if let Some(field) = field {
+        p.name = field.clone();
+    } else if p.name.is_empty() {
+        return Err(bad());
+    }
+    if let Some(ct) = &ct
+        && ct.value.starts_with("multipart/")
+    {
+        if field.is_some() {
+            return Err(bad()); // Only one level of nesting is allowed.
         }
+        let boundary = ct.param("boundary").ok_or_else(bad)?;
+        return get_multipart(data, boundary.as_bytes(), Some(&p.name), q);
+    }
+    let is_text = match &ct {
+        None => true,
+        Some(ct) => ct.value == "text/plain",
+    };
+    if is_text && !is_file {
+        q.form.insert(p.name, togs(data)?);
+    } else {
+        p.data = Arc::new(GVec::from(data));
+        q.parts.push(p);
     }
     Ok(())
 }
@@ -424,6 +813,8 @@ struct Buffer<'a> {
     timer: std::time::SystemTime,
     ss: Arc<SharedState>,
     uid: String,
+    /// The connection is from a trusted proxy, so the X-Real-IP header gives the client address.
+    proxy: bool,
 }
 
 impl<'a> Drop for Buffer<'a> {
@@ -437,6 +828,9 @@ impl<'a> Buffer<'a> {
     /// Create a new Buffer.
     fn new(stream: tokio::net::tcp::ReadHalf<'a>, ss: Arc<SharedState>, uid: String) -> Self {
         let limit = ss.u_budget(uid.clone());
+        let proxy = uid
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ss.proxies.contains(&ip.to_canonical()));
         let mut result = Self {
             stream,
             buf: [0; 2048],
@@ -447,6 +841,7 @@ impl<'a> Buffer<'a> {
             ss,
             u: UseInfo::default(),
             uid,
+            proxy,
         };
         result.u.used[U_COUNT] = 1;
         result.u.limit = limit;
@@ -517,17 +912,15 @@ impl<'a> Buffer<'a> {
     /// Read specified number of bytes.
     async fn read(&mut self, n: usize) -> Result<GVec<u8>, Error> {
         let mut to = GVec::new();
-        loop {
+        while to.len() < n {
             if self.i == self.n {
                 self.fill().await?;
             }
-            let b = self.buf[self.i];
-            self.i += 1;
-            to.push(b);
-            if to.len() == n {
-                return Ok(to);
-            }
+            let take = (n - to.len()).min(self.n - self.i);
+            to.extend_from_slice(&self.buf[self.i..self.i + take]);
+            self.i += take;
         }
+        Ok(to)
     }
 }
 
@@ -1,7 +1,8 @@
 use crate::HashMap;
 use rustdb::{GenTransaction, Transaction};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, mpsc, oneshot};
 
 /// Global shared state.
@@ -24,8 +25,23 @@ pub struct SharedState {
     /// For notifying tasks waiting for update transaction.
     pub wait_tx: broadcast::Sender<()>,
 
-    /// Server is master ( not replicating another database ).
-    pub is_master: bool,
+    /// Server is master ( not replicating another database ), set when a replication server is promoted ( see is_master ).
+    pub master: AtomicBool,
+
+    /// Server has been superseded by a promoted replication server, and no longer accepts updates ( see log.Term ).
+    pub fenced: AtomicBool,
+
+    /// Promotion that fenced this server: the name of the promoted replication server and a random value ( see tasks::promote ).
+    pub fenced_by: Mutex<String>,
+
+    /// Replication term, incremented each time a replication server is promoted ( see log.Term ).
+    pub term: AtomicU64,
+
+    /// Task that replicates the master, aborted when promoted ( see tasks::promote ).
+    pub backup: Mutex<Option<tokio::task::AbortHandle>>,
+
+    /// Seconds without contact from the master after which a replication server promotes itself ( zero means never ).
+    pub promote_after: u64,
 
     /// URL of master server.
     pub replicate_source: String,
@@ -33,12 +49,78 @@ pub struct SharedState {
     /// Cookies for replication.
     pub replicate_credentials: String,
 
-    /// Denial of service limits.
+    /// API token for replication.
+    pub replicate_token: String,
+
+    /// Name of this replication server, used by the master to track its position ( see log.Replica ).
+    pub replicate_name: String,
+
+    /// How this replication server handles requests that update the database.
+    pub rep_writes: crate::replicate::Writes,
+
+    /// HTTP client for requests forwarded to the master.
+    pub rep_client: reqwest::Client,
+
+    /// Status of replication from the master ( for a replication server ).
+    pub rep_status: Mutex<crate::replicate::ReplicaStatus>,
+
+    /// Replication servers connected to this server.
+    pub rep_conns: Mutex<HashMap<u64, crate::replicate::ReplicaConn>>,
+
+    /// Server secret key for signed and encrypted cookies.
+    pub key: crate::crypt::Key,
+
+    /// Password hashing cost parameters.
+    pub pw_params: argon2::Params,
+
+    /// Denial of service limits ( token bucket capacities ).
     pub dos_limit: UA,
 
+    /// Denial of service refill rates ( per second ).
+    pub dos_rate: UA,
+
+    /// Multiple of the per address limits for each /24 ( IPv4 ) or /64 ( IPv6 ) subnet, zero means no subnet limits.
+    pub dos_subnet: u64,
+
+    /// Multiple of the per address limits for each /16 ( IPv4 ) or /32 ( IPv6 ) network, zero means no network limits.
+    pub dos_network: u64,
+
+    /// Limit on concurrent connections.
+    pub conn_limit: Arc<tokio::sync::Semaphore>,
+
+    /// Limit on concurrent connections from an IP address ( zero means no limit ).
+    pub ip_conn_limit: usize,
+
+    /// Addresses of proxies trusted to give the client address in the X-Real-IP header.
+    pub proxies: Vec<std::net::IpAddr>,
+
+    /// Number of current connections from each IP address.
+    pub ip_conns: Mutex<HashMap<String, usize>>,
+
+    /// Limit on concurrent read-only request transactions.
+    pub read_limit: tokio::sync::Semaphore,
+
+    /// Limit on queued read-write request transactions.
+    pub write_limit: tokio::sync::Semaphore,
+
+    /// Limit on recent failed logins for an IP address.
+    pub fail_limit: u64,
+
     /// Information for mitigating DoS attacks
     pub dos: Mutex<HashMap<String, UseInfo>>,
 
+    /// Interval for saving DoS information to file ( seconds, zero means not saved ).
+    pub dos_save: u64,
+
+    /// IP address allow and deny rules, loaded when login.IpRule changes ( see set_ip_rules ).
+    pub ip_rules: Mutex<Arc<crate::iprules::IpRules>>,
+
+    /// OpenID Connect issuer metadata and keys, cached by issuer URL.
+    pub oidc: Mutex<HashMap<String, crate::oidc::Issuer>>,
+
+    /// MAC of the last audit log record ( loaded when first needed ).
+    pub audit: tokio::sync::Mutex<Option<Vec<u8>>>,
+
     /// Trace time to process each request.
     pub tracetime: bool,
 
@@ -49,8 +131,31 @@ pub struct SharedState {
     pub tracemem: bool,
 }
 
+/// Offset added to Unix time ( in microseconds ) to get audit log time ( see date.Ticks ).
+pub const TICKS_OFFSET: i64 = 62135596800000000 + 366 * 24 * 3600 * 1000000;
+
+/// Maximum length of audit event detail ( in characters ).
+const AUDIT_DETAIL_LIMIT: usize = 10000;
+
+/// Administrative action to be recorded in the audit log ( see AUDIT ).
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AuditEvent {
+    /// Kind of action, e.g. Execute.
+    pub action: String,
+    /// Details of the action, e.g. SQL text.
+    pub detail: String,
+    /// Outcome, blank means the transaction error ( if any ) or ok.
+    pub outcome: String,
+}
+
+/// File where usage information is saved ( see --dos-save ).
+pub const DOS_FILE: &str = "rustweb.dos";
+
+/// Number of kinds of usage.
+pub const U_KINDS: usize = 5;
+
 /// Usage array ( total or limit ).
-pub type UA = [u64; 4];
+pub type UA = [u64; U_KINDS];
 
 /// Index into usage array for number of requests.
 pub const U_COUNT: usize = 0;
@@ -64,73 +169,208 @@ pub const U_CPU: usize = 2;
 /// Index into usage array for response write usage .
 pub const U_WRITE: usize = 3;
 
+/// Index into usage array for memory usage ( peak memory used by a transaction, in KB ).
+pub const U_MEM: usize = 4;
+
 /// Information kept on usage for each user.
-#[derive(Debug)]
+/// Each kind of usage is limited by a token bucket : the bucket holds up to limit tokens, which are used by requests,
+/// and refilled at rate tokens per second. Rather than the tokens, the amount taken from the bucket ( used ) is kept.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct UseInfo {
-    /// Running totals of amount of CPU/IO etc. used.
+    /// Amount of CPU/IO etc. used, not yet refilled.
     pub used: UA,
-    /// Limits on usage.
+    /// Limits on usage ( bucket capacity ).
     pub limit: UA,
+    /// Refill rate ( per second ).
+    pub rate: UA,
+    /// Refill carried forward ( in millionths ).
+    #[serde(skip)]
+    carry: UA,
+    /// Time of last refill.
+    #[serde(skip, default = "Instant::now")]
+    refilled: Instant,
+    /// Number of recent failed logins ( see LOGINFAIL ).
+    pub fails: u64,
 }
 
 impl UseInfo {
-    fn new(limit: &UA) -> Self {
+    fn new(limit: &UA, rate: &UA) -> Self {
         Self {
-            used: [0, 0, 0, 0],
+            used: [0; U_KINDS],
             limit: *limit,
+            rate: *rate,
+            carry: [0; U_KINDS],
+            refilled: Instant::now(),
+            fails: 0,
+        }
+    }
+
+    /// Refill the buckets for the time elapsed since the last refill.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let micros = now.duration_since(self.refilled).as_micros() as u64;
+        self.refilled = now;
+        for i in 0..U_KINDS {
+            let amount = self.rate[i]
+                .saturating_mul(micros)
+                .saturating_add(self.carry[i]);
+            self.carry[i] = amount % 1_000_000;
+            self.used[i] = self.used[i].saturating_sub(amount / 1_000_000);
+            if self.used[i] == 0 {
+                self.carry[i] = 0;
+            }
+        }
+    }
+
+    /// Seconds until cost ( for requests count ) can be used, and one unit of other usage is available.
+    fn retry_secs(&self, cost: u64) -> u64 {
+        let mut result = 0;
+        for i in 0..U_KINDS {
+            let need = if i == U_COUNT { cost } else { 1 };
+            let excess = (self.used[i] + need).saturating_sub(self.limit[i]);
+            if excess > 0 {
+                let secs = match self.rate[i] {
+                    0 => 3600,
+                    rate => excess.div_ceil(rate),
+                };
+                result = result.max(secs);
+            }
+        }
+        result
+    }
+
+    /// Highest percentage of any limit used.
+    fn percent(&self) -> u64 {
+        let mut result = 0;
+        for i in 0..U_KINDS {
+            let pct = match self.limit[i] {
+                0 => 100,
+                limit => (self.used[i] as u128 * 100 / limit as u128) as u64,
+            };
+            result = result.max(pct);
         }
+        result
     }
 }
 
+/// Usage information saved to file ( see u_save ).
+#[derive(serde::Serialize, serde::Deserialize)]
+struct UseSnapshot {
+    /// Unix time when saved ( seconds ).
+    time: u64,
+    /// Usage for each user.
+    users: Vec<(String, UseInfo)>,
+}
+
 impl Default for UseInfo {
     fn default() -> Self {
-        Self::new(&[0, 0, 0, 0])
+        Self::new(&[0; U_KINDS], &[0; U_KINDS])
     }
 }
 
 impl SharedState {
-    /// Get the usage budget for specified user ( difference between limit and usage ).
+    /// Get the keys under which usage by specified user is accounted, with the multiple of the default limits for each key.
+    /// For an IP address, usage is also accounted to its subnet and network ( see dos_subnet, dos_network ),
+    /// so an attacker cannot get a fresh budget by changing address.
+    fn u_levels(&self, uid: &str) -> Vec<(String, u64)> {
+        let mut result = vec![(uid.to_string(), 1)];
+        if let Ok(ip) = uid.parse::<std::net::IpAddr>() {
+            for (bits4, bits6, scale) in [(24, 64, self.dos_subnet), (16, 32, self.dos_network)] {
+                if scale > 0 {
+                    result.push((ip_prefix(ip, bits4, bits6), scale));
+                }
+            }
+        }
+        result
+    }
+
+    /// Get the usage budget for specified user ( tokens remaining in each bucket, the least for any level ).
     pub fn u_budget(&self, uid: String) -> UA {
         let mut m = self.dos.lock().unwrap();
-        let info = m
-            .entry(uid)
-            .or_insert_with(|| UseInfo::new(&self.dos_limit));
-        let mut result = [0; 4];
-        for (i, item) in result.iter_mut().enumerate() {
-            if info.used[i] >= info.limit[i] {
-                return [0; 4];
+        let mut result = [u64::MAX; U_KINDS];
+        for (key, scale) in self.u_levels(&uid) {
+            let info = m.entry(key).or_insert_with(|| {
+                UseInfo::new(
+                    &scaled(&self.dos_limit, scale),
+                    &scaled(&self.dos_rate, scale),
+                )
+            });
+            info.refill();
+            for (i, item) in result.iter_mut().enumerate() {
+                if info.used[i] >= info.limit[i] {
+                    return [0; U_KINDS];
+                }
+                *item = (*item).min(info.limit[i] - info.used[i]);
             }
-            *item = info.limit[i] - info.used[i];
         }
         result
     }
 
-    /// Increment usage linits for specified user.
+    /// Get headers for a 429 ( Too Many Requests ) response to specified user, where the request costs cost.
+    /// The headers describe the level with the longest time to wait.
+    pub fn u_limit_headers(&self, uid: &str, cost: u64) -> Vec<(&'static str, String)> {
+        let mut m = self.dos.lock().unwrap();
+        // ( retry, limit, remaining ) for the level with the longest time to wait.
+        let mut worst: Option<(u64, u64, u64)> = None;
+        for (key, _scale) in self.u_levels(uid) {
+            if let Some(info) = m.get_mut(&key) {
+                info.refill();
+                let retry = info.retry_secs(cost);
+                if worst.is_none_or(|(r, _, _)| retry > r) {
+                    let limit = info.limit[U_COUNT];
+                    worst = Some((retry, limit, limit.saturating_sub(info.used[U_COUNT])));
+                }
+            }
+        }
+        let Some((retry, limit, remaining)) = worst else {
+            return Vec::new();
+        };
+        let retry = retry.max(1).to_string();
+        vec![
+            ("Retry-After", retry.clone()),
+            ("RateLimit-Limit", limit.to_string()),
+            ("RateLimit-Remaining", remaining.to_string()),
+            ("RateLimit-Reset", retry),
+        ]
+    }
+
+    /// Increment usage linits for specified user ( at each level ).
     pub fn u_inc(&self, uid: &str, amount: UA) {
         let mut m = self.dos.lock().unwrap();
-        if let Some(info) = m.get_mut(uid) {
+        for (key, _scale) in self.u_levels(uid) {
+            let Some(info) = m.get_mut(&key) else {
+                continue;
+            };
             for (i, amt) in amount.iter().enumerate() {
                 info.used[i] += *amt;
             }
             if self.tracedos {
                 println!(
-                    "uid={} Count={}% Read={}% Cpu={}% Write={}%",
-                    uid,
+                    "uid={} Count={}% Read={}% Cpu={}% Write={}% Mem={}%",
+                    key,
                     100. * info.used[0] as f32 / info.limit[0] as f32,
                     100. * info.used[1] as f32 / info.limit[1] as f32,
                     100. * info.used[2] as f32 / info.limit[2] as f32,
                     100. * info.used[3] as f32 / info.limit[3] as f32,
+                    100. * info.used[4] as f32 / info.limit[4] as f32,
                 );
             }
         }
     }
 
-    /// Set the limits for specified user. Result is no limit exceeded.
+    /// Set the limits for specified user. Refill rates are in proportion to the default rates. Result is no limit exceeded.
     pub fn u_set_limits(&self, uid: String, limit: UA) -> bool {
         let mut m = self.dos.lock().unwrap();
         let info = m.entry(uid).or_default();
+        info.refill();
         info.limit = limit;
-        for i in 0..4 {
+        for (i, rate) in info.rate.iter_mut().enumerate() {
+            *rate = match self.dos_limit[i] {
+                0 => 0,
+                d => (limit[i] as u128 * self.dos_rate[i] as u128 / d as u128) as u64,
+            };
+        }
+        for i in 0..U_KINDS {
             if info.used[i] >= info.limit[i] {
                 return false;
             }
@@ -138,28 +378,258 @@ impl SharedState {
         true
     }
 
-    /// Deflate old usage by 10% periodically. Items with zero usage are removed.
+    /// Record a failed login for specified IP address. Result is the number of recent failed logins.
+    pub fn u_fail(&self, ip: &str) -> u64 {
+        let mut m = self.dos.lock().unwrap();
+        let info = m
+            .entry(ip.to_string())
+            .or_insert_with(|| UseInfo::new(&self.dos_limit, &self.dos_rate));
+        info.fails += 1;
+        if self.tracedos {
+            println!("ip={} failed logins={}", ip, info.fails);
+        }
+        info.fails
+    }
+
+    /// Get the limits for specified user, if known.
+    pub fn u_limits(&self, uid: &str) -> Option<UA> {
+        let m = self.dos.lock().unwrap();
+        m.get(uid).map(|info| info.limit)
+    }
+
+    /// Get number of recent failed logins for specified IP address.
+    pub fn u_fails(&self, ip: &str) -> u64 {
+        let m = self.dos.lock().unwrap();
+        m.get(ip).map_or(0, |info| info.fails)
+    }
+
+    /// Refill all buckets periodically, and forget one failed login. Items with full buckets and no failed logins are removed.
     pub fn u_decay(&self) {
         let mut m = self.dos.lock().unwrap();
         m.retain(|_uid, info| {
-            let mut nz = false;
-            for i in 0..4 {
-                if info.used[i] > 0 {
-                    info.used[i] -= 1 + info.used[i] / 10;
-                }
-                nz = nz || info.used[i] > 0;
+            if info.fails > 0 {
+                info.fails -= 1;
             }
-            nz
+            info.refill();
+            info.fails > 0 || info.used.iter().any(|u| *u > 0)
         });
     }
 
+    /// Get current usage for all users as a JSON array, highest percentage used first ( see DOSINFO ).
+    pub fn u_info(&self) -> String {
+        let mut m = self.dos.lock().unwrap();
+        let mut list = Vec::new();
+        for (uid, info) in m.iter_mut() {
+            info.refill();
+            let pct = info.percent();
+            list.push(serde_json::json!({
+                "uid": uid,
+                "used": info.used,
+                "limit": info.limit,
+                "rate": info.rate,
+                "pct": pct,
+                "fails": info.fails,
+                "retry": if pct >= 100 { info.retry_secs(1) } else { 0 },
+            }));
+        }
+        list.sort_by(|a, b| b["pct"].as_u64().cmp(&a["pct"].as_u64()));
+        serde_json::Value::Array(list).to_string()
+    }
+
+    /// Clear usage and failed logins for specified user ( all users if uid is blank ). Limits are kept. Result is number of entries reset.
+    pub fn u_reset(&self, uid: &str) -> u64 {
+        let mut m = self.dos.lock().unwrap();
+        let mut result = 0;
+        for (id, info) in m.iter_mut() {
+            if uid.is_empty() || id == uid {
+                info.used = [0; U_KINDS];
+                info.carry = [0; U_KINDS];
+                info.fails = 0;
+                result += 1;
+            }
+        }
+        result
+    }
+
+    /// Save usage information to file.
+    pub fn u_save(&self, path: &str) {
+        let snapshot = {
+            let mut m = self.dos.lock().unwrap();
+            let mut users = Vec::new();
+            for (uid, info) in m.iter_mut() {
+                info.refill();
+                users.push((uid.clone(), info.clone()));
+            }
+            UseSnapshot {
+                time: unix_secs(),
+                users,
+            }
+        };
+        let json = serde_json::to_vec(&snapshot).unwrap();
+        let tmp = format!("{path}.tmp");
+        if let Err(e) = std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, path)) {
+            println!("Error saving usage to {path} error={e}");
+        }
+    }
+
+    /// Load usage information saved by u_save. Buckets are refilled and failed logins forgotten for the time elapsed since saving.
+    pub fn u_load(&self, path: &str) {
+        let Ok(bytes) = std::fs::read(path) else {
+            return;
+        };
+        let snapshot: UseSnapshot = match serde_json::from_slice(&bytes) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Error loading usage from {path} error={e}");
+                return;
+            }
+        };
+        let elapsed = unix_secs().saturating_sub(snapshot.time);
+        let mut m = self.dos.lock().unwrap();
+        for (uid, mut info) in snapshot.users {
+            if let Some(t) = Instant::now().checked_sub(Duration::from_secs(elapsed)) {
+                info.refilled = t;
+            }
+            info.refill();
+            info.fails = info.fails.saturating_sub(elapsed / 10);
+            m.insert(uid, info);
+        }
+        println!("Loaded usage for {} users from {path}", m.len());
+    }
+
+    /// Check whether requests to path are allowed from specified IP address ( see login.IpRule ).
+    pub fn ip_allowed(&self, ip: &str, path: &str) -> bool {
+        let rules = self.ip_rules.lock().unwrap().clone();
+        rules.allowed(ip, path)
+    }
+
+    /// Load the IP address rules from the database ( when the server starts, or login.IpRule has changed ).
+    pub fn set_ip_rules(&self, db: &rustdb::DB) {
+        let rules = Arc::new(crate::iprules::IpRules::load(db));
+        *self.ip_rules.lock().unwrap() = rules;
+    }
+
+    /// Count a connection from specified IP address. Result is None if the IP address has too many connections.
+    pub fn ip_connect(self: &Arc<Self>, ip: &str) -> Option<IpConn> {
+        let mut m = self.ip_conns.lock().unwrap();
+        let n = m.entry(ip.to_string()).or_default();
+        if self.ip_conn_limit > 0 && *n >= self.ip_conn_limit {
+            return None;
+        }
+        *n += 1;
+        Some(IpConn {
+            ss: self.clone(),
+            ip: ip.to_string(),
+        })
+    }
+
+    /// Process a transaction for a http request. If there are too many concurrent read-only transactions,
+    /// or too many read-write transactions waiting, the status is set to 503 ( Service Unavailable ) instead.
+    /// The permit is only held while the database runs the transaction, the caller then does any delay or wait ( see wait ).
+    pub async fn process_request(&self, trans: Trans) -> Trans {
+        let limit = if trans.readonly {
+            &self.read_limit
+        } else {
+            &self.write_limit
+        };
+        let trans = match limit.try_acquire() {
+            Ok(_permit) => self.run(trans).await,
+            Err(_) => {
+                let mut trans = trans;
+                trans.busy();
+                return trans;
+            }
+        };
+        self.finish(trans).await
+    }
+
     /// Called to notify tasks waiting for new transaction.
     pub fn new_trans(&self) {
         let _ = self.wait_tx.send(());
     }
 
+    /// Whether the server is master ( not replicating another database ).
+    pub fn is_master(&self) -> bool {
+        self.master.load(Ordering::SeqCst)
+    }
+
+    /// Whether the server has been superseded by a promoted replication server ( see fence ).
+    pub fn is_fenced(&self) -> bool {
+        self.fenced.load(Ordering::SeqCst)
+    }
+
+    /// Current replication term.
+    pub fn term(&self) -> u64 {
+        self.term.load(Ordering::SeqCst)
+    }
+
+    /// Load the replication term, and whether the server has been fenced ( from log.Term ).
+    pub fn load_term(&self) {
+        let apd = self.spd.new_reader();
+        let db = rustdb::Database::new(apd, "", self.bmap.clone());
+        if let Some(t) = db.get_table(&rustdb::ObjRef::new("log", "Term")) {
+            for (pp, off) in t.scan(&db) {
+                let p = &pp.borrow();
+                let a = t.access(p, off);
+                self.term.store(a.int(0) as u64, Ordering::SeqCst);
+                if a.int(1) != 0 {
+                    self.fenced.store(true, Ordering::SeqCst);
+                    self.master.store(false, Ordering::SeqCst);
+                    *self.fenced_by.lock().unwrap() = a.str(&db, 2);
+                }
+            }
+        }
+    }
+
+    /// Save the replication term, and whether the server has been fenced, and by which promotion ( see log.SetTerm ).
+    pub async fn save_term(&self, term: u64, fenced: bool) {
+        let by = self.fenced_by.lock().unwrap().replace('\'', "''");
+        let mut st = Trans::new();
+        st.x.qy.sql = Arc::new(format!("EXEC log.SetTerm({term},{},'{by}')", fenced as u8));
+        st = self.process(st).await;
+        if !st.x.rp.err.is_empty() {
+            println!("Saving replication term failed error={}", st.x.rp.err);
+        }
+    }
+
+    /// Stop accepting updates, as a replication server has been promoted with a later term ( see replicate::serve ).
+    /// by identifies the promotion. Fencing is saved, so it persists when the server is restarted, until an administrator unfences the server.
+    pub async fn fence(&self, term: u64, by: &str) {
+        if self.is_fenced() && term <= self.term() {
+            return;
+        }
+        println!("Fenced by promoted replication server term={term} by={by}");
+        self.master.store(false, Ordering::SeqCst);
+        self.fenced.store(true, Ordering::SeqCst);
+        *self.fenced_by.lock().unwrap() = by.to_string();
+        self.term.fetch_max(term, Ordering::SeqCst);
+        self.save_term(self.term(), true).await;
+    }
+
+    /// Accept updates again after the server has been fenced ( see UNFENCE ). A server that is not replicating becomes master.
+    pub async fn unfence(&self) {
+        if !self.fenced.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        self.fenced_by.lock().unwrap().clear();
+        println!("Unfenced term={}", self.term());
+        if self.backup.lock().unwrap().is_none() {
+            self.master.store(true, Ordering::SeqCst);
+            let _ = self.sleep_tx.send(1);
+            let _ = self.email_tx.send(());
+        }
+        self.save_term(self.term(), false).await;
+    }
+
     /// Process a server transaction.
-    pub async fn process(&self, mut trans: Trans) -> Trans {
+    pub async fn process(&self, trans: Trans) -> Trans {
+        let mut trans = self.finish(self.run(trans).await).await;
+        self.wait(&mut trans).await;
+        trans
+    }
+
+    /// Run the transaction on the database ( read-only copy, or the update task ).
+    async fn run(&self, mut trans: Trans) -> Trans {
         let start = std::time::SystemTime::now();
         let mut trans = if trans.readonly {
             // println!("Processing readonly");
@@ -169,8 +639,7 @@ impl SharedState {
             let task = tokio::task::spawn_blocking(move || {
                 let apd = spd.new_reader();
                 let db = rustdb::Database::new(apd, "", bmap);
-                let sql = trans.x.qy.sql.clone();
-                db.run(&sql, &mut trans.x);
+                trans.run(&db);
                 trans
             });
             task.await.unwrap()
@@ -185,25 +654,49 @@ impl SharedState {
             self.new_trans();
         }
         trans.run_time = start.elapsed().unwrap();
+        trans
+    }
 
-        let ext = trans.x.get_extension();
-        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+    /// Act on the transaction after it has run: deferred SQL, audit events, emails, timed jobs etc.
+    async fn finish(&self, mut trans: Trans) -> Trans {
+        let mut ext = trans.x.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
             trans.uid = ext.uid.clone();
-            if self.is_master {
+            if self.is_master() {
                 if ext.sleep > 0 {
                     let _ = self.sleep_tx.send(ext.sleep);
                 }
                 if ext.tx_email {
                     let _ = self.email_tx.send(());
                 }
-            }
-            if ext.trans_wait {
-                let mut wait_rx = self.wait_tx.subscribe();
-                tokio::select! {
-                   _ = wait_rx.recv() => {}
-                   _ = tokio::time::sleep(Duration::from_secs(600)) => {}
+                for sql in std::mem::take(&mut ext.deferred) {
+                    self.deferred(sql).await;
+                }
+                if !ext.audit.is_empty() {
+                    let events = std::mem::take(&mut ext.audit);
+                    let x = &trans.x;
+                    self.audit(events, ext, &x.qy.path, &x.rp.err, x.qy.now)
+                        .await;
+                }
+            } else if !self.is_fenced() && !self.replicate_source.is_empty() {
+                // Replication server, the deferred SQL ( e.g. inserting a new session ) is run by the master,
+                // and audit events are recorded in the master's audit log.
+                if !ext.deferred.is_empty() {
+                    let sql = std::mem::take(&mut ext.deferred);
+                    crate::replicate::defer_to_master(self, sql).await;
+                }
+                if !ext.audit.is_empty() {
+                    let events = std::mem::take(&mut ext.audit);
+                    let x = &trans.x;
+                    crate::replicate::audit_to_master(
+                        self, events, ext, &x.qy.path, &x.rp.err, x.qy.now,
+                    )
+                    .await;
                 }
             }
+            if let Some(code) = ext.shutdown {
+                self.terminate(code);
+            }
             if ext.trans_flush {
                 let spd = self.spd.clone();
                 let _ = tokio::task::spawn_blocking(move || {
@@ -220,14 +713,166 @@ impl SharedState {
         trans
     }
 
+    /// Delay after a failed login ( see LOGINFAIL ), and wait for a new transaction ( see TRANSWAIT ).
+    /// Called after process_request has released its permit, so waiting requests do not hold up other requests.
+    pub async fn wait(&self, trans: &mut Trans) {
+        let (mut delay, mut trans_wait) = (0, false);
+        let ext = trans.x.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            (delay, trans_wait) = (ext.delay, ext.trans_wait);
+        }
+        trans.x.set_extension(ext);
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        if trans_wait {
+            let mut wait_rx = self.wait_tx.subscribe();
+            tokio::select! {
+               _ = wait_rx.recv() => {}
+               _ = tokio::time::sleep(Duration::from_secs(600)) => {}
+            }
+        }
+    }
+
+    /// Append events to the audit log. Each record holds a MAC of its fields and the MAC of the previous record,
+    /// so records cannot be altered, inserted or removed without detection ( see /audit-Log ).
+    pub async fn audit(
+        &self,
+        events: Vec<AuditEvent>,
+        ext: &TransExt,
+        path: &str,
+        err: &str,
+        now: i64,
+    ) {
+        let user = ext.uid.strip_prefix('u');
+        let user = user.and_then(|s| s.parse::<i64>().ok()).unwrap_or(0);
+        let time = now + TICKS_OFFSET;
+        let mut last = self.audit.lock().await;
+        let prev = last.get_or_insert_with(|| self.audit_last());
+        for e in events {
+            let outcome = if !e.outcome.is_empty() {
+                e.outcome
+            } else if !err.is_empty() {
+                err.to_string()
+            } else {
+                "ok".to_string()
+            };
+            let fields = [
+                time.to_string(),
+                user.to_string(),
+                ext.ip.clone(),
+                path.to_string(),
+                e.action,
+                e.detail,
+                outcome,
+            ];
+            let mac = crate::crypt::audit_mac(&self.key, prev, &fields);
+            let q = |s: &str| format!("'{}'", s.replace('\'', "''"));
+            let sql = format!(
+                "INSERT INTO audit.Log(Time,User,Ip,Path,Action,Detail,Outcome,Hash) VALUES ({time},{user},{},{},{},{},{},0x{})",
+                q(&fields[2]),
+                q(&fields[3]),
+                q(&fields[4]),
+                q(&fields[5]),
+                q(&fields[6]),
+                crate::crypt::hex(&mac)
+            );
+            if self.deferred(sql).await {
+                *prev = mac;
+            }
+        }
+    }
+
+    /// Get the MAC of the last audit log record ( empty if there are no records ).
+    fn audit_last(&self) -> Vec<u8> {
+        let apd = self.spd.new_reader();
+        let db = rustdb::Database::new(apd, "", self.bmap.clone());
+        let mut result = Vec::new();
+        if let Some(t) = db.get_table(&rustdb::ObjRef::new("audit", "Log")) {
+            for (pp, off) in t.scan(&db) {
+                let p = &pp.borrow();
+                result = t.access(p, off).bin(&db, 7).to_vec();
+            }
+        }
+        result
+    }
+
+    /// Run SQL queued by DEFER as a separate read-write transaction. Result is true if there was no error.
+    pub async fn deferred(&self, sql: String) -> bool {
+        let mut trans = Trans::new();
+        trans.x.qy.sql = Arc::new(sql);
+        let (reply, rx) = oneshot::channel::<Trans>();
+        let _ = self.update_tx.send(UpdateMessage { trans, reply }).await;
+        let trans = rx.await.unwrap();
+        let ok = trans.x.rp.err.is_empty();
+        if !ok {
+            println!("Deferred SQL error={}", trans.x.rp.err);
+        }
+        if trans.updates > 0 {
+            self.new_trans();
+        }
+        ok
+    }
+
     pub fn terminate( &self, code: i64 )
     {
+       if self.dos_save > 0 {
+           self.u_save(DOS_FILE);
+       }
        self.spd.shutdown();
        println!("Terminating code = {}", code);
        std::process::exit(code as i32)
     }
 }
 
+/// Multiply usage array by scale.
+fn scaled(ua: &UA, scale: u64) -> UA {
+    ua.map(|x| x.saturating_mul(scale))
+}
+
+/// Get the prefix of an IP address ( in CIDR notation ), with the given number of bits for IPv4 or IPv6.
+fn ip_prefix(ip: std::net::IpAddr, bits4: u32, bits6: u32) -> String {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    match ip.to_canonical() {
+        IpAddr::V4(a) => {
+            let a = Ipv4Addr::from(u32::from(a) & (!0 << (32 - bits4)));
+            format!("{a}/{bits4}")
+        }
+        IpAddr::V6(a) => {
+            let a = Ipv6Addr::from(u128::from(a) & (!0 << (128 - bits6)));
+            format!("{a}/{bits6}")
+        }
+    }
+}
+
+/// Seconds since the Unix epoch.
+fn unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Connection from an IP address, counted until dropped ( see ip_connect ).
+pub struct IpConn {
+    ss: Arc<SharedState>,
+    ip: String,
+}
+
+impl Drop for IpConn {
+    fn drop(&mut self) {
+        let mut m = self.ss.ip_conns.lock().unwrap();
+        if let Some(n) = m.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                m.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Seconds to wait before retrying when the server is busy.
+pub const BUSY_RETRY: u64 = 1;
+
 /// Transaction to be processed.
 pub struct Trans {
     pub x: GenTransaction,
@@ -236,6 +881,8 @@ pub struct Trans {
     pub run_time: core::time::Duration,
     pub updates: usize,
     pub uid: String,
+    /// Peak memory used by the transaction ( in bytes ).
+    pub mem_used: u64,
 }
 
 impl Trans {
@@ -247,6 +894,7 @@ impl Trans {
             run_time: Duration::from_micros(0),
             updates: 0,
             uid: String::new(),
+            mem_used: 0,
         }
     }
 
@@ -260,6 +908,7 @@ impl Trans {
         let mut result = Self::make();
         let mut ext = TransExt::new();
         ext.ss = Some(ss);
+        ext.ip = ip.clone();
         ext.uid = ip;
         result.x.ext = ext;
         result
@@ -279,8 +928,10 @@ impl Trans {
     pub async fn convert_to_pdf(&mut self) {
         let source = std::mem::take(&mut self.x.rp.output);
         let task = tokio::task::spawn_blocking(move || {
-            let mut w = pdf_min::Writer::default();
-            w.fetcher = Some(Box::new(PdfFetcher));
+            let mut w = pdf_min::Writer {
+                fetcher: Some(Box::new(PdfFetcher)),
+                ..Default::default()
+            };
             pdf_min::html(&mut w, &source);
             w.finish();
             w.b.b
@@ -289,6 +940,106 @@ impl Trans {
         self.x.rp.output = pdf;
     }
 
+    /// Run the transaction SQL, measuring the peak memory used. The transaction is aborted if it exceeds the memory limit.
+    /// If the transaction is aborted or rolled back by an error, any SQL it deferred is discarded ( see DEFER ).
+    pub fn run(&mut self, db: &rustdb::DB) {
+        let mut limit = u64::MAX;
+        let ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            limit = ext.mem_limit;
+        }
+        self.x.set_extension(ext);
+        let sql = self.x.qy.sql.clone();
+        let mut m = crate::meter::Metered::new(std::mem::take(&mut self.x), limit);
+        db.run(&sql, &mut m);
+        self.mem_used = m.peak();
+        let exceeded = m.exceeded().is_some();
+        self.x = m.x;
+        if exceeded || !self.x.rp.err.is_empty() {
+            let mut ext = self.x.get_extension();
+            if let Some(ext) = ext.downcast_mut::<TransExt>() {
+                ext.deferred.clear();
+            }
+            self.x.set_extension(ext);
+        }
+        if exceeded {
+            // 429 = HTTP Too Many Requests.
+            self.x.rp.output = Vec::new();
+            self.x.rp.status_code = 429;
+        }
+    }
+
+    /// Set the memory limit for the transaction ( in bytes ).
+    pub fn set_mem_limit(&mut self, limit: u64) {
+        let mut ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.mem_limit = limit;
+        }
+        self.x.set_extension(ext);
+    }
+
+    /// Set the response to 503 ( Service Unavailable ) as the server is busy.
+    pub fn busy(&mut self) {
+        self.x.rp.status_code = 503;
+        let retry = rustdb::alloc::GString::from(BUSY_RETRY.to_string().as_str());
+        self.x.rp.headers.push((rustdb::alloc::GString::from("Retry-After"), retry));
+    }
+
+    /// Get the cost of the request ( see RATECOST ).
+    pub fn cost(&mut self) -> u64 {
+        let mut result = 1;
+        let ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            result = ext.cost;
+        }
+        self.x.set_extension(ext);
+        result
+    }
+
+    /// Id of the first log.Transaction record to be streamed to a replication server ( see LOGSTREAM ).
+    pub fn log_stream(&mut self) -> Option<u64> {
+        let mut result = None;
+        let ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            result = ext.log_stream;
+        }
+        self.x.set_extension(ext);
+        result
+    }
+
+    /// Whether the transaction runs a request forwarded by a replication server ( see LOGFORWARD ).
+    pub fn log_forward(&mut self) -> bool {
+        let mut result = false;
+        let ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            result = ext.log_forward;
+        }
+        self.x.set_extension(ext);
+        result
+    }
+
+    /// Whether the transaction requested promotion to master ( see PROMOTE ).
+    pub fn promote(&mut self) -> bool {
+        let mut result = false;
+        let ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            result = ext.promote;
+        }
+        self.x.set_extension(ext);
+        result
+    }
+
+    /// Whether the transaction requested the server is unfenced ( see UNFENCE ).
+    pub fn unfence(&mut self) -> bool {
+        let mut result = false;
+        let ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            result = ext.unfence;
+        }
+        self.x.set_extension(ext);
+        result
+    }
+
     pub fn no_log(&mut self) -> bool {
         let mut result = false;
         let ext = self.x.get_extension();
@@ -316,8 +1067,12 @@ pub struct UpdateMessage {
 pub struct TransExt {
     /// Shared State.
     pub ss: Option<Arc<SharedState>>,
+    /// Server key, when there is no shared state ( see recover ).
+    pub key: Option<crate::crypt::Key>,
     /// Id of requestor ( IP address or logged in user id ).
     pub uid: String,
+    /// IP address of requestor.
+    pub ip: String,
     /// Signals there is new email to be sent.
     pub tx_email: bool,
     /// Signals time to sleep.
@@ -326,26 +1081,91 @@ pub struct TransExt {
     pub trans_wait: bool,
     /// Signals wait for transactions to be flushed
     pub trans_flush: bool,
+    /// Stream log.Transaction records to a replication server, starting from the specified Id ( see LOGSTREAM ).
+    pub log_stream: Option<u64>,
+    /// Run a request forwarded by a replication server ( see LOGFORWARD ).
+    pub log_forward: bool,
     /// Transform html output to pdf.
     pub to_pdf: bool,
     /// Do not log transaction.
     pub no_log: bool,
+    /// SQL to be run after the transaction completes ( see DEFER ).
+    pub deferred: Vec<String>,
+    /// Session cookie value set by this transaction.
+    pub session: Option<String>,
+    /// Delay before responding, in milliseconds ( see LOGINFAIL ).
+    pub delay: u64,
+    /// Cost of the request, in terms of the requests count limit ( see RATECOST ).
+    pub cost: u64,
+    /// Limit on memory used by the transaction, in bytes ( see U_MEM ).
+    pub mem_limit: u64,
+    /// Events to be recorded in the audit log.
+    pub audit: Vec<AuditEvent>,
+    /// Terminate server with specified exit code after the transaction ( see SHUTDOWN ).
+    pub shutdown: Option<i64>,
+    /// Promote replication server to master after the transaction ( see PROMOTE ).
+    pub promote: bool,
+    /// Accept updates again after the server has been fenced, after the transaction ( see UNFENCE ).
+    pub unfence: bool,
 }
 
 impl TransExt {
-    fn new() -> Box<Self> {
+    pub fn new() -> Box<Self> {
         Box::new(Self {
             ss: None,
+            key: None,
             uid: String::new(),
+            ip: String::new(),
             tx_email: false,
             sleep: 0,
             trans_wait: false,
             trans_flush: false,
+            log_stream: None,
+            log_forward: false,
             to_pdf: false,
             no_log: false,
+            deferred: Vec::new(),
+            session: None,
+            delay: 0,
+            cost: 1,
+            mem_limit: u64::MAX,
+            audit: Vec::new(),
+            shutdown: None,
+            promote: false,
+            unfence: false,
         })
     }
 
+    /// Record a failed login for the requestor IP address. Result is the number of recent failed logins.
+    /// The response is delayed, doubling with each recent failure ( up to 30 seconds ).
+    /// When the limit is reached, login.Alert is called.
+    pub fn login_fail(&mut self) -> u64 {
+        let Some(ss) = &self.ss else {
+            return 0;
+        };
+        let fails = ss.u_fail(&self.ip);
+        self.delay = (100 << (fails - 1).min(10)).min(30_000);
+        if fails == ss.fail_limit {
+            let ip = self.ip.replace('\'', "''");
+            let msg = format!("Login blocked for IP address {ip} after {fails} failed attempts");
+            self.deferred.push(format!("EXEC login.Alert('{msg}')"));
+        }
+        fails
+    }
+
+    /// Queue an event for the audit log. The detail is truncated if it is very long.
+    pub fn audit(&mut self, action: &str, detail: &str, outcome: &str) {
+        let detail = match detail.char_indices().nth(AUDIT_DETAIL_LIMIT) {
+            Some((i, _)) => &detail[..i],
+            None => detail,
+        };
+        self.audit.push(AuditEvent {
+            action: action.to_string(),
+            detail: detail.to_string(),
+            outcome: outcome.to_string(),
+        });
+    }
+
     /// Set limits, returns false if limit exceeded.
     pub fn set_dos(&self, uid: String, to: UA) -> bool {
         if let Some(ss) = &self.ss {
@@ -446,3 +1266,28 @@ fn jpg_to_image( w: &mut Writer, file_bytes: &[u8] ) -> Image
    // Make the PDF Image from the ImageSpec.
    Image::new(&ims, &mut w.b)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustdb::{Database, MemFile, SharedPagedData};
+
+    /// Run the SQL in a new transaction, result is the deferred SQL.
+    fn deferred(db: &rustdb::DB, sql: &str) -> Vec<String> {
+        let mut t = Trans::new();
+        t.x.qy.sql = Arc::new(sql.to_string());
+        t.run(db);
+        let ext = t.x.get_extension();
+        ext.downcast_ref::<TransExt>().unwrap().deferred.clone()
+    }
+
+    #[test]
+    fn defer_discarded_on_error() {
+        let spd = SharedPagedData::new(MemFile::new());
+        let db = Database::new(spd.new_writer(), "", Arc::new(crate::builtins::get_bmap()));
+        let sql = "DECLARE x int SET x = DEFER( 'SELECT 1' )";
+        assert_eq!(deferred(&db, sql), vec!["SELECT 1".to_string()]);
+        let sql = "DECLARE x int SET x = DEFER( 'SELECT 1' ) THROW 'failed'";
+        assert!(deferred(&db, sql).is_empty());
+    }
+}
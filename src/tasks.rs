@@ -1,9 +1,10 @@
-use crate::share::{SharedState, Trans};
+use crate::share::{AuditEvent, SharedState, Trans, TransExt};
 use rustdb::{Database, Part, alloc::GVec};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use tokio::sync::mpsc;
 
-/// Task that calls u_decay every 10 seconds
+/// Task that calls u_decay every 10 seconds ( to remove unused entries and forget failed logins )
 pub async fn u_decay_loop(ss: Arc<SharedState>) {
     loop {
         tokio::time::sleep(core::time::Duration::from_secs(10)).await;
@@ -11,10 +12,21 @@ pub async fn u_decay_loop(ss: Arc<SharedState>) {
     }
 }
 
-/// Task for backing up master database
+/// Task that saves usage information every dos_save seconds ( so it is kept when the server restarts ).
+pub async fn u_save_loop(ss: Arc<SharedState>) {
+    loop {
+        tokio::time::sleep(core::time::Duration::from_secs(ss.dos_save)).await;
+        let ssc = ss.clone();
+        let _ = tokio::task::spawn_blocking(move || ssc.u_save(crate::share::DOS_FILE)).await;
+    }
+}
+
+/// Task for backing up master database.
+/// If there is no contact with the master for promote_after seconds, the server promotes itself ( see promote ).
 pub async fn backup_loop(is_new: bool, state: Arc<SharedState>) {
+    let client = reqwest::Client::new();
     if is_new {
-        let sql = rget(state.clone(), "/log-getall").await;
+        let sql = rget(&client, &state, "/log-getall").await;
         let sql = std::str::from_utf8(&sql).unwrap().to_string();
         let mut st = Trans::new();
         st.log = false;
@@ -34,21 +46,197 @@ pub async fn backup_loop(is_new: bool, state: Arc<SharedState>) {
         let s = std::str::from_utf8(&st.x.rp.output).unwrap();
         s.parse::<u64>().unwrap()
     };
-    println!("Backup from fetch={}", fetch);
+    let start = std::time::Instant::now();
+    loop {
+        println!("Backup from fetch={}", fetch);
+        state.rep_status.lock().unwrap().fetch = fetch;
+        if let Err(e) = backup_stream(&client, &state, &mut fetch).await {
+            println!("Backup stream error={e}");
+            {
+                let mut st = state.rep_status.lock().unwrap();
+                st.connected = false;
+                st.error = e.to_string();
+            }
+            if state.promote_after > 0 {
+                let contact = state.rep_status.lock().unwrap().contact.unwrap_or(start);
+                let secs = contact.elapsed().as_secs();
+                if secs >= state.promote_after {
+                    println!("No contact with master for {secs}s");
+                    tokio::spawn(promote(state.clone(), String::new(), String::new()));
+                    return;
+                }
+            }
+            // Wait before reconnecting after error.
+            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        }
+    }
+}
+
+/// Receive log.Transaction records streamed from the master ( see replicate::serve ), starting from fetch.
+/// Each batch is saved in a single transaction, then acknowledged. Returns if the connection fails,
+/// or the master has been superseded ( its replication term is earlier than one already seen ).
+async fn backup_stream(
+    client: &reqwest::Client,
+    state: &Arc<SharedState>,
+    fetch: &mut u64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use reqwest::header::{CONNECTION, UPGRADE};
+    use tokio::io::AsyncWriteExt;
+    let name = urlencoding::encode(&state.replicate_name);
+    let url = format!("/log-stream?k={fetch}&name={name}");
+    let req = rrequest(client, state, &url)
+        .header(CONNECTION, "Upgrade")
+        .header(UPGRADE, crate::replicate::PROTOCOL);
+    let response = req.send().await?;
+    let status = response.status();
+    if status == reqwest::StatusCode::CONFLICT {
+        return Err("master has been superseded by a promoted replication server".into());
+    }
+    if status != reqwest::StatusCode::SWITCHING_PROTOCOLS {
+        return Err(format!("bad response status = {status}").into());
+    }
+    let mut s = response.upgrade().await?;
+    let timeout = core::time::Duration::from_secs(crate::replicate::TIMEOUT);
+    let start = std::time::Instant::now();
     loop {
-        let url = format!("/log-get?k={fetch}");
-        let ser = rget(state.clone(), &url).await;
-        if !ser.is_empty() {
+        // Reconnect periodically when using a session cookie, so the session is renewed.
+        if !state.replicate_credentials.is_empty()
+            && start.elapsed().as_secs() >= crate::replicate::RENEW
+        {
+            return Ok(());
+        }
+        let batch = tokio::time::timeout(timeout, crate::replicate::read_frame(&mut s)).await??;
+        if batch.first != *fetch {
+            return Err(format!("unexpected batch first={} fetch={fetch}", batch.first).into());
+        }
+        {
+            let mut st = state.rep_status.lock().unwrap();
+            if batch.term < st.term {
+                let msg = format!("master term={} superseded by term={}", batch.term, st.term);
+                return Err(msg.into());
+            }
+            st.term = batch.term;
+        }
+        let n = batch.records.len();
+        state.rep_status.lock().unwrap().received(batch.end, *fetch);
+        if n > 0 {
             let mut st = Trans::new();
-            let mut part = Part::default();
-            part.data = Arc::new(ser);
-            st.x.qy.parts.push(part);
-            st.x.qy.sql = Arc::new("EXEC log.Save()".to_string());
-            state.process(st).await;
-            println!("Saved Transaction Id={fetch}");
-            fetch += 1;
+            for data in batch.records {
+                let mut part = Part::default();
+                part.data = Arc::new(GVec::from(&*data));
+                st.x.qy.parts.push(part);
+            }
+            st.x.qy.sql = Arc::new(format!("EXEC log.SaveBatch({n})"));
+            st = state.process(st).await;
+            if !st.x.rp.err.is_empty() {
+                return Err(format!("saving transactions failed error={}", st.x.rp.err).into());
+            }
+            *fetch += n as u64;
+            println!("Saved Transactions Id={}..{}", batch.first, *fetch - 1);
+            state.rep_status.lock().unwrap().received(batch.end, *fetch);
             state.new_trans();
         }
+        s.write_all(&fetch.to_le_bytes()).await?;
+    }
+}
+
+/// Promote a replication server to master ( see PROMOTE and --promote-after ).
+/// Replication is stopped, outstanding transactions are applied ( see log.Roll ), then the server starts logging
+/// transactions, sending emails and running timed jobs. The replication term is incremented ( see log.Term ),
+/// and the old master is fenced so it no longer accepts updates ( see fence_loop ).
+/// If log.Roll fails, the server is not promoted, and replication resumes.
+/// uid and ip identify the requestor for the audit log ( blank for automatic promotion ).
+pub async fn promote(state: Arc<SharedState>, uid: String, ip: String) {
+    // Only a replication server has a backup task, and it can only be promoted once.
+    let Some(backup) = state.backup.lock().unwrap().take() else {
+        return;
+    };
+    backup.abort();
+    println!("Promoting to master");
+    loop {
+        let mut st = Trans::new_with_state(state.clone(), String::new());
+        st.x.qy.sql = Arc::new("EXEC log.Roll()".to_string());
+        st = state.process(st).await;
+        if !st.x.rp.err.is_empty() {
+            let err = format!("promotion failed, log.Roll error={}", st.x.rp.err);
+            println!("{err}");
+            state.rep_status.lock().unwrap().error = err;
+            resume_backup(state);
+            return;
+        }
+        // Roll stops early if a function is updated, so repeat until complete.
+        if String::from_utf8_lossy(&st.x.rp.output).contains("Roll complete") {
+            break;
+        }
+    }
+    let term = state.term().max(state.rep_status.lock().unwrap().term) + 1;
+    state.term.store(term, Ordering::SeqCst);
+    state.save_term(term, false).await;
+    state.rep_status.lock().unwrap().connected = false;
+    state.master.store(true, Ordering::SeqCst);
+    println!("Promoted to master term={term}");
+
+    // Start timed jobs and send queued emails.
+    let _ = state.sleep_tx.send(1);
+    let _ = state.email_tx.send(());
+
+    let mut ext = TransExt::new();
+    ext.uid = uid;
+    ext.ip = ip;
+    let event = AuditEvent {
+        action: "PROMOTE".to_string(),
+        detail: format!("term={term}"),
+        outcome: String::new(),
+    };
+    let now = Trans::new().x.qy.now;
+    state.audit(vec![event], &ext, "", "", now).await;
+
+    // Two replication servers may be promoted with the same term, so the promotion is identified by the name and a random value.
+    let token = crate::crypt::new_token();
+    let id = format!("{} {}", state.replicate_name, &token[..8]);
+    fence_loop(&state, term, &id).await;
+}
+
+/// Restart the backup task after a failed promotion, after a pause, as the master may still be unavailable.
+fn resume_backup(state: Arc<SharedState>) {
+    let ssc = state.clone();
+    let task = tokio::spawn(async move {
+        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        backup_loop(false, ssc).await
+    });
+    *state.backup.lock().unwrap() = Some(task.abort_handle());
+}
+
+/// Fence the old master after promotion, so it no longer accepts updates ( see replicate::serve ).
+/// Retries every 10 seconds until the old master responds. id identifies the promotion. If the old master
+/// has been fenced by another promotion ( with the same term ), this server is fenced, so there is only one master.
+async fn fence_loop(state: &SharedState, term: u64, id: &str) {
+    use reqwest::header::{CONNECTION, UPGRADE};
+    let client = reqwest::Client::new();
+    let fence = crate::replicate::fence_value(state, term, id);
+    let url = format!("/log-stream?k=0&fence={}", urlencoding::encode(&fence));
+    loop {
+        let req = rrequest(&client, state, &url)
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, crate::replicate::PROTOCOL);
+        match req.send().await {
+            Ok(r) if r.status() == reqwest::StatusCode::CONFLICT => {
+                println!("Old master fenced term={term}");
+                return;
+            }
+            Ok(r) if r.status() == reqwest::StatusCode::GONE => {
+                println!("Old master already fenced by another promoted replication server");
+                state.fence(term, "").await;
+                return;
+            }
+            Ok(r) if r.status() == reqwest::StatusCode::SWITCHING_PROTOCOLS => {
+                println!("Old master not fenced, it has a later term or a different server key");
+                return;
+            }
+            Ok(r) => println!("Fencing old master bad response status = {}", r.status()),
+            Err(e) => println!("Fencing old master error={e}"),
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
     }
 }
 
@@ -70,18 +258,28 @@ async fn sleep_real(secs: u64) {
     }
 }
 
+/// Build a request to the master server, with replication credentials.
+fn rrequest(client: &reqwest::Client, state: &SharedState, query: &str) -> reqwest::RequestBuilder {
+    with_credentials(client.get(state.replicate_source.clone() + query), state)
+}
+
+/// Add replication credentials to a request to the master server.
+pub fn with_credentials(
+    req: reqwest::RequestBuilder,
+    state: &SharedState,
+) -> reqwest::RequestBuilder {
+    let mut req = req.header("Cookie", state.replicate_credentials.clone());
+    if !state.replicate_token.is_empty() {
+        req = req.bearer_auth(&state.replicate_token);
+    }
+    req
+}
+
 /// Get data from master server, retries in case of error
-async fn rget(state: Arc<SharedState>, query: &str) -> GVec<u8> {
-    // get a client builder
-    let client = reqwest::Client::builder()
-        .default_headers(reqwest::header::HeaderMap::new())
-        .build()
-        .unwrap();
+async fn rget(client: &reqwest::Client, state: &SharedState, query: &str) -> GVec<u8> {
     loop {
         let mut retry_delay = true;
-        let req = client
-            .get(state.replicate_source.clone() + query)
-            .header("Cookie", state.replicate_credentials.clone());
+        let req = rrequest(client, state, query);
 
         tokio::select! {
             response = req.send() =>
@@ -126,7 +324,7 @@ pub async fn sleep_loop(mut rx: mpsc::UnboundedReceiver<u64>, state: Arc<SharedS
             ns = rx.recv() => { sleep_micro = ns.unwrap(); }
             _ = tokio::time::sleep(core::time::Duration::from_micros(sleep_micro)) =>
             {
-              if state.is_master
+              if state.is_master()
               {
                 let mut st = Trans::new();
                 st.x.qy.sql = Arc::new("EXEC timed.Run()".to_string());
@@ -137,6 +335,20 @@ pub async fn sleep_loop(mut rx: mpsc::UnboundedReceiver<u64>, state: Arc<SharedS
     }
 }
 
+/// Task that deletes log.Transaction records every hour when master ( see log.Prune ).
+/// The transaction is not logged, so it is not replicated, or replayed by recover.
+pub async fn prune_loop(state: Arc<SharedState>) {
+    loop {
+        if state.is_master() {
+            let mut st = Trans::new();
+            st.x.qy.sql =
+                Arc::new("SELECT NOLOG() EXEC log.Prune( log.NextTransaction() )".to_string());
+            state.process(st).await;
+        }
+        tokio::time::sleep(core::time::Duration::from_secs(3600)).await;
+    }
+}
+
 /// Task that sends emails
 pub async fn email_loop(mut rx: mpsc::UnboundedReceiver<()>, state: Arc<SharedState>) {
     loop {